@@ -0,0 +1,78 @@
+// Compare view rendering speed with and without the brickmap acceleration
+// structure on a big, mostly empty model.
+//
+// Run with `cargo bench --bench trace`.
+
+use std::time::Instant;
+
+use glam::{ivec3, IVec3};
+use voxelize::{build_view, Body, Brickmap, Camera, VoxelGrid};
+
+const N: i32 = 256;
+
+fn sparse_model() -> VoxelGrid<u8> {
+    let mut grid = VoxelGrid::new(IVec3::ZERO, IVec3::splat(N));
+
+    // A few small balls scattered around the volume.
+    for (i, center) in [
+        ivec3(40, 40, 40),
+        ivec3(200, 60, 120),
+        ivec3(128, 128, 128),
+        ivec3(60, 210, 200),
+        ivec3(220, 220, 30),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let r = 12;
+        for z in -r..=r {
+            for y in -r..=r {
+                for x in -r..=r {
+                    let d = ivec3(x, y, z);
+                    if d.length_squared() <= r * r {
+                        grid.set(center + d, i as u8 + 1);
+                    }
+                }
+            }
+        }
+    }
+
+    // Floor plate.
+    for y in 0..N {
+        for x in 0..N {
+            grid.set(ivec3(x, y, 0), 9);
+        }
+    }
+
+    grid
+}
+
+fn bench<B: Body<Value = u8>>(name: &str, model: &B, camera: &Camera) {
+    let start = Instant::now();
+    let view = build_view(model, camera);
+    println!(
+        "{name:>10}: {:>8.1} ms, {} pixels",
+        start.elapsed().as_secs_f64() * 1000.0,
+        view.len()
+    );
+}
+
+fn main() {
+    let grid = sparse_model();
+
+    let start = Instant::now();
+    let brickmap = Brickmap::new(grid.clone());
+    println!(
+        "brickmap built in {:.1} ms",
+        start.elapsed().as_secs_f64() * 1000.0
+    );
+
+    for (name, camera) in [
+        ("north", Camera::OBLIQUE_NORTH),
+        ("east", Camera::OBLIQUE_EAST),
+    ] {
+        println!("{name}");
+        bench("grid", &grid, &camera);
+        bench("brickmap", &brickmap, &camera);
+    }
+}
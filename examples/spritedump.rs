@@ -1,51 +1,46 @@
-use std::collections::HashSet;
+use anyhow::{anyhow, Result};
+use image::Rgba;
+use voxelize::{build_view, Camera, Canvas, Image, Outline, Rect, Scene};
 
-use anyhow::Result;
-use dot_vox::DotVoxData;
-use image::{ImageBuffer, Rgba};
-
-// Render an oblique sprite from a VOX model.
-
-pub type Pixel = Rgba<u8>;
-pub type Image = ImageBuffer<Pixel, Vec<u8>>;
+// Render oblique sprites of a VOX scene seen from the front and the back.
 
 fn main() -> Result<()> {
     // Load VOX model from CLI parameter
     let path = std::env::args().nth(1).expect("No file path provided");
 
-    let scene = dot_vox::load(&path).expect("Failed to load");
-    let model = &scene.models[0];
-
-    // Get dimensions of model and build a blank image that's x+z, y+z big.
-
-    let w = model.size.x as u32 + model.size.z as u32;
-    let h = model.size.y as u32 + model.size.z as u32;
+    let data = dot_vox::load(&path).map_err(|e| anyhow!(e))?;
+    let scene = Scene::try_from(&data)?;
 
-    let mut canvas = Image::new(w * 2, h * 2);
-
-    draw_model(&scene, &mut canvas, (0, 0), false);
-    draw_model(&scene, &mut canvas, (0, h), true);
-
-    // Comparison image 1
-    if let Some(front_path) = std::env::args().nth(2) {
-        blit(
-            &image::open(front_path)
-                .expect("failed to load reference image")
-                .into(),
-            &mut canvas,
-            (w, 0),
-        );
-    }
+    let front = draw_scene(&data, &scene, &Camera::OBLIQUE_NORTH);
+    let back = draw_scene(&data, &scene, &Camera::OBLIQUE_SOUTH);
 
-    // Comparison image 2
-    if let Some(rear_path) = std::env::args().nth(3) {
-        blit(
-            &image::open(rear_path)
-                .expect("failed to load reference image")
-                .into(),
-            &mut canvas,
-            (w, h),
-        );
+    // Comparison images go next to the renders.
+    let reference = |n| -> Result<Option<Image>> {
+        match std::env::args().nth(n) {
+            Some(path) => Ok(Some(image::open(path)?.into())),
+            None => Ok(None),
+        }
+    };
+    let rows = [(front, reference(2)?), (back, reference(3)?)];
+
+    let w = rows.iter().map(|(a, _)| a.width()).max().unwrap_or(0);
+    let ref_w = rows
+        .iter()
+        .filter_map(|(_, b)| b.as_ref().map(|b| b.width()))
+        .max()
+        .unwrap_or(0);
+    let row_h =
+        |(a, b): &(Image, Option<Image>)| a.height().max(b.as_ref().map_or(0, |b| b.height()));
+    let h = rows.iter().map(row_h).sum();
+
+    let mut canvas = Image::new(w + ref_w, h);
+    let mut y = 0;
+    for row in &rows {
+        image::imageops::overlay(&mut canvas, &row.0, 0, y as i64);
+        if let Some(reference) = &row.1 {
+            blit(reference, &mut canvas, (w, y));
+        }
+        y += row_h(row);
     }
 
     canvas.save("output.png")?;
@@ -53,45 +48,22 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn draw_model(scene: &DotVoxData, canvas: &mut Image, position: (u32, u32), flip: bool) {
-    let model = &scene.models[0];
-    let mut filled = HashSet::new();
-    for z in 0..model.size.z {
-        for y in 0..model.size.y {
-            for x in 0..model.size.x {
-                let Some(voxel) = model
-                    .voxels
-                    .iter()
-                    .find(|v| v.x == x as u8 && v.y == y as u8 && v.z == z as u8)
-                else {
-                    continue;
-                };
-                let color = scene.palette[voxel.i as usize];
-                let y = if flip { y } else { model.size.y - y - 1 };
-                let z = model.size.z - z - 1;
-                let x = x + z / 2;
-                let y = y + z / 2;
-                let (x, y) = (x + position.0, y + position.1);
-                canvas.put_pixel(x, y, Rgba([color.r, color.g, color.b, 255]));
-                filled.insert((x, y));
-            }
-        }
-    }
+fn draw_scene(data: &dot_vox::DotVoxData, scene: &Scene, camera: &Camera) -> Image {
+    let view = build_view(scene, camera);
 
     // Trace black outline around the drawing.
-    for &(x, y) in &filled {
-        for dx in -1i32..=1 {
-            for dy in -1i32..=1 {
-                if dx.abs() + dy.abs() == 1 {
-                    let x = (x as i32 + dx) as u32;
-                    let y = (y as i32 + dy) as u32;
-                    if !filled.contains(&(x, y)) {
-                        canvas.put_pixel(x, y, Rgba([0, 0, 0, 255]));
-                    }
-                }
-            }
-        }
+    let outline = Outline::default().pixels(&view, camera, |_| Rgba([0, 0, 0, 255]));
+
+    let rect = Rect::from_points(view.keys().chain(outline.keys()).copied());
+    let mut canvas = Canvas::new(rect, &Default::default());
+    for (pos, (_, idx)) in view {
+        let color = data.palette[idx as usize];
+        canvas.put(pos, Rgba([color.r, color.g, color.b, 255]));
+    }
+    for (pos, color) in outline {
+        canvas.put(pos, color);
     }
+    canvas.into_image()
 }
 
 fn blit(src: &Image, canvas: &mut Image, (px, py): (u32, u32)) {
@@ -0,0 +1,195 @@
+use glam::{IVec3, Vec3};
+
+use crate::{Body, BoundingBox, TraceStep, Traversal};
+
+/// Width of a brick cube in cells.
+pub const BRICK_SIZE: i32 = 8;
+
+/// Two-level acceleration structure for ray casting.
+///
+/// Wraps a body and records which bricks of `BRICK_SIZE`³ cells have any
+/// content. Ray casts step over empty bricks whole and only visit individual
+/// cells inside occupied bricks.
+pub struct Brickmap<B> {
+    body: B,
+    /// Brick coordinates of the first brick.
+    origin: IVec3,
+    /// Size of the map in bricks.
+    size: IVec3,
+    occupied: Vec<bool>,
+}
+
+fn index(size: IVec3, brick: IVec3) -> usize {
+    (brick.x + brick.y * size.x + brick.z * size.x * size.y) as usize
+}
+
+impl<B: Body> Brickmap<B> {
+    pub fn new(body: B) -> Self {
+        let aabb = body.bounding_box();
+        let min = aabb.min.floor().as_ivec3();
+        let max = aabb.max.ceil().as_ivec3().max(min);
+
+        let origin = min.div_euclid(IVec3::splat(BRICK_SIZE));
+        let size = (max - IVec3::ONE).div_euclid(IVec3::splat(BRICK_SIZE)) - origin + IVec3::ONE;
+        let size = size.max(IVec3::ZERO);
+
+        let mut occupied = vec![false; size.x as usize * size.y as usize * size.z as usize];
+        for z in min.z..max.z {
+            for y in min.y..max.y {
+                for x in min.x..max.x {
+                    let cell = IVec3::new(x, y, z);
+                    if body.sample(cell.as_vec3()).is_some() {
+                        let brick = cell.div_euclid(IVec3::splat(BRICK_SIZE)) - origin;
+                        occupied[index(size, brick)] = true;
+                    }
+                }
+            }
+        }
+
+        Brickmap {
+            body,
+            origin,
+            size,
+            occupied,
+        }
+    }
+
+    /// Access the wrapped body.
+    pub fn body(&self) -> &B {
+        &self.body
+    }
+
+    fn is_occupied(&self, brick: IVec3) -> bool {
+        let brick = brick - self.origin;
+        if brick.min_element() < 0 || brick.cmpge(self.size).any() {
+            return false;
+        }
+        self.occupied[index(self.size, brick)]
+    }
+}
+
+impl<B: Body> Body for Brickmap<B> {
+    type Value = B::Value;
+
+    fn sample(&self, pos: Vec3) -> Option<Self::Value> {
+        self.body.sample(pos)
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        self.body.bounding_box()
+    }
+
+    fn normal(&self, pos: Vec3) -> Vec3 {
+        self.body.normal(pos)
+    }
+
+    fn cast(&self, origin: Vec3, dir: Vec3, len: f32) -> Option<(TraceStep, Self::Value)> {
+        let cells = Traversal::new(origin, dir, 1);
+
+        for brick in Traversal::new(origin, dir, BRICK_SIZE).take_while(|step| step.t < len) {
+            if !self.is_occupied(brick.cell) {
+                continue;
+            }
+
+            let min = brick.cell * BRICK_SIZE;
+            let max = min + IVec3::splat(BRICK_SIZE);
+
+            // Trace the cells inside the brick.
+            let inner = if brick.normal == IVec3::ZERO {
+                cells.clone()
+            } else {
+                let entry = TraceStep {
+                    cell: entry_cell(&cells, &brick, min, max),
+                    ..brick
+                };
+                Traversal::from_step(origin, dir, 1, entry)
+            };
+
+            let hit = inner
+                .take_while(|step| {
+                    step.t < len && step.cell.cmpge(min).all() && step.cell.cmplt(max).all()
+                })
+                .find_map(|step| self.body.sample(step.cell.as_vec3()).map(|val| (step, val)));
+
+            if hit.is_some() {
+                return hit;
+            }
+        }
+
+        None
+    }
+}
+
+/// Find the cell where cell traversal `cells` enters the brick spanning
+/// `min` to `max` at brick traversal step `brick`.
+///
+/// The cell is reconstructed from the exact boundary crossings so that the
+/// result matches a traversal that runs through all the cells, even when the
+/// ray runs exactly along cell edges.
+fn entry_cell(cells: &Traversal, brick: &TraceStep, min: IVec3, max: IVec3) -> IVec3 {
+    let t = brick.t;
+    let step = cells.step();
+    let entry_axis = (0..3).find(|&i| brick.normal[i] != 0).unwrap();
+    let start = cells.origin().floor().as_ivec3();
+
+    // Boundary crossings on other axes at the same ray parameter are ordered
+    // by axis.
+    let crossed =
+        |axis: usize, t_boundary: f32| t_boundary < t || (t_boundary == t && axis < entry_axis);
+
+    let mut ret = IVec3::ZERO;
+    for axis in 0..3 {
+        if axis == entry_axis {
+            ret[axis] = if step[axis] > 0 {
+                min[axis]
+            } else {
+                max[axis] - 1
+            };
+            continue;
+        }
+
+        if step[axis] == 0 {
+            ret[axis] = start[axis];
+            continue;
+        }
+
+        let pos = cells.origin()[axis] + t * cells.dir()[axis];
+        let mut c = (pos.floor() as i32).clamp(min[axis], max[axis] - 1);
+        while crossed(axis, cells.exit_t(c, axis)) {
+            c += step[axis];
+        }
+        while c != start[axis] && !crossed(axis, cells.exit_t(c - step[axis], axis)) {
+            c -= step[axis];
+        }
+        ret[axis] = c;
+    }
+
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{build_view, Camera, VoxelGrid};
+    use glam::ivec3;
+
+    #[test]
+    fn brickmap_view_matches_plain_view() {
+        let mut grid = VoxelGrid::new(ivec3(-20, -5, 3), ivec3(50, 40, 30));
+        for z in 3..33i32 {
+            for y in -5..35 {
+                for x in -20..30 {
+                    if (x * 7 + y * 13 + z * 5).rem_euclid(97) == 0 {
+                        grid.set(ivec3(x, y, z), (x + y + z) as u8);
+                    }
+                }
+            }
+        }
+
+        let brickmap = Brickmap::new(grid.clone());
+        for yaw in [0.0f32, 0.4, 1.3, std::f32::consts::FRAC_PI_2, 2.9, 4.0] {
+            let camera = Camera::OBLIQUE_NORTH.rotated(yaw.to_degrees());
+            assert_eq!(build_view(&grid, &camera), build_view(&brickmap, &camera));
+        }
+    }
+}
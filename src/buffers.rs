@@ -0,0 +1,354 @@
+use glam::{ivec2, vec3, IVec2, IVec3, Mat4, Vec3};
+use image::{GrayImage, ImageBuffer, Luma, Rgba};
+
+use std::collections::HashMap;
+
+use crate::{pixel_ray, trace_pixel, Body, Camera, Image, NormalEstimator, Pixel, Rect, TraceStep};
+
+/// Per-pixel render layers for a view of a model.
+///
+/// All layers cover the same pixel rectangle in the image space of
+/// `project` and are stored row by row.
+#[derive(Clone, Debug)]
+pub struct RenderBuffers<T> {
+    /// The camera the view was rendered with.
+    pub camera: Camera,
+    /// Inverse of the camera matrix, for mapping pixels back to view rays.
+    inverse: Mat4,
+    rect: Rect,
+    /// Distance along the view ray to the hit voxel surface.
+    depth: Vec<f32>,
+    /// Outward normal of the voxel face the view ray hit.
+    normal: Vec<IVec3>,
+    /// Cell of the hit voxel.
+    position: Vec<IVec3>,
+    value: Vec<Option<T>>,
+}
+
+/// Render the model into per-pixel layers by tracing a ray through every
+/// view pixel.
+///
+/// Covers the same pixels as `build_view`.
+pub fn render_buffers<T>(model: &dyn Body<Value = T>, camera: &Camera) -> RenderBuffers<T> {
+    let aabb = model.bounding_box();
+    let rect = aabb.view_rect(camera);
+    let inverse = Mat4::from(*camera).inverse();
+    let perspective = camera.is_perspective();
+
+    let hits = (rect.min.y..rect.max.y).flat_map(|y| {
+        (rect.min.x..rect.max.x)
+            .map(move |x| trace_pixel(model, &aabb, &inverse, perspective, ivec2(x, y)))
+    });
+    RenderBuffers::new(camera, inverse, rect, hits)
+}
+
+/// Multithreaded version of `render_buffers`.
+///
+/// Image rows are split between the threads of the current rayon thread
+/// pool. The result is identical to the one from `render_buffers`.
+#[cfg(feature = "rayon")]
+pub fn render_buffers_par<T: Send>(
+    model: &(dyn Body<Value = T> + Sync),
+    camera: &Camera,
+) -> RenderBuffers<T> {
+    use rayon::prelude::*;
+
+    let aabb = model.bounding_box();
+    let rect = aabb.view_rect(camera);
+    let inverse = Mat4::from(*camera).inverse();
+    let perspective = camera.is_perspective();
+
+    let hits: Vec<_> = (rect.min.y..rect.max.y)
+        .into_par_iter()
+        .flat_map_iter(|y| {
+            (rect.min.x..rect.max.x)
+                .map(move |x| trace_pixel(model, &aabb, &inverse, perspective, ivec2(x, y)))
+        })
+        .collect();
+    RenderBuffers::new(camera, inverse, rect, hits)
+}
+
+impl<T> RenderBuffers<T> {
+    /// Fill the layers from the trace results of the pixels of `rect`, row
+    /// by row.
+    fn new(
+        camera: &Camera,
+        inverse: Mat4,
+        rect: Rect,
+        hits: impl IntoIterator<Item = Option<(TraceStep, T)>>,
+    ) -> Self {
+        let size = (rect.max - rect.min).max(IVec2::ZERO);
+        let n = (size.x * size.y) as usize;
+
+        let mut ret = RenderBuffers {
+            camera: *camera,
+            inverse,
+            rect,
+            depth: vec![f32::INFINITY; n],
+            normal: vec![IVec3::ZERO; n],
+            position: vec![IVec3::ZERO; n],
+            value: Vec::with_capacity(n),
+        };
+
+        for hit in hits {
+            let i = ret.value.len();
+            match hit {
+                Some((step, val)) => {
+                    ret.depth[i] = step.t;
+                    ret.normal[i] = step.normal;
+                    ret.position[i] = step.cell;
+                    ret.value.push(Some(val));
+                }
+                None => ret.value.push(None),
+            }
+        }
+
+        ret
+    }
+
+    /// Pixels covered by the layers.
+    pub fn rect(&self) -> Rect {
+        self.rect
+    }
+
+    pub fn width(&self) -> u32 {
+        (self.rect.max.x - self.rect.min.x).max(0) as u32
+    }
+
+    pub fn height(&self) -> u32 {
+        (self.rect.max.y - self.rect.min.y).max(0) as u32
+    }
+
+    fn index(&self, pixel: IVec2) -> Option<usize> {
+        if !self.rect.contains(pixel) {
+            return None;
+        }
+        let pos = pixel - self.rect.min;
+        let i = pos.y as usize * self.width() as usize + pos.x as usize;
+        self.value[i].is_some().then_some(i)
+    }
+
+    /// Value of the voxel seen at `pixel`.
+    pub fn value(&self, pixel: IVec2) -> Option<&T> {
+        self.index(pixel).and_then(|i| self.value[i].as_ref())
+    }
+
+    /// Distance along the view ray from the ray origin to the voxel surface
+    /// seen at `pixel`.
+    pub fn depth(&self, pixel: IVec2) -> Option<f32> {
+        self.index(pixel).map(|i| self.depth[i])
+    }
+
+    /// Outward normal of the voxel face seen at `pixel`.
+    pub fn normal(&self, pixel: IVec2) -> Option<IVec3> {
+        self.index(pixel).map(|i| self.normal[i])
+    }
+
+    /// Cell of the voxel seen at `pixel`.
+    pub fn position(&self, pixel: IVec2) -> Option<IVec3> {
+        self.index(pixel).map(|i| self.position[i])
+    }
+
+    /// Iterate the pixels that hit a voxel.
+    pub fn pixels(&self) -> impl Iterator<Item = IVec2> + '_ {
+        let w = self.width().max(1) as i32;
+        self.value
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| v.is_some())
+            .map(move |(i, _)| self.rect.min + ivec2(i as i32 % w, i as i32 / w))
+    }
+
+    /// Normal of the voxel face seen at `pixel` in screen space.
+    pub fn screen_normal(&self, pixel: IVec2) -> Option<Vec3> {
+        let normal = self.normal(pixel)?.as_vec3();
+        Some(self.to_screen(pixel, normal))
+    }
+
+    /// Rotate world space `normal` of the surface seen at `pixel` into
+    /// screen space.
+    ///
+    /// The x axis points right, y up and z towards the viewer along the view
+    /// ray.
+    pub fn to_screen(&self, pixel: IVec2, normal: Vec3) -> Vec3 {
+        to_screen(&self.inverse, pixel, normal)
+    }
+
+    /// Export the depth layer as a 16-bit grayscale image.
+    ///
+    /// The depth range of the view is stretched over the full value range,
+    /// the nearest surface is white and empty pixels are black.
+    pub fn depth_image(&self) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        let (near, far) = self
+            .pixels()
+            .filter_map(|p| self.depth(p))
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(a, b), z| {
+                (a.min(z), b.max(z))
+            });
+        let range = (far - near).max(f32::EPSILON);
+
+        self.layer_image(|pixel| {
+            let z = self.depth(pixel)?;
+            Some(Luma(
+                [65535 - ((z - near) / range * 65534.0).round() as u16],
+            ))
+        })
+    }
+
+    /// Export the normal layer as an RGB normal map in screen space.
+    ///
+    /// Normal components from -1 to 1 map to color channel values from 0 to
+    /// 255, red is right, green is up and blue is towards the viewer.
+    /// Empty pixels are transparent.
+    pub fn normal_image(&self) -> Image {
+        self.encode_normals(|pixel| Some(self.normal(pixel)?.as_vec3()))
+    }
+
+    /// Export a normal map with normals estimated from the voxels of `model`
+    /// around the hit voxels instead of the face normals.
+    pub fn smooth_normal_image<B: Body + ?Sized>(
+        &self,
+        model: &B,
+        estimator: NormalEstimator,
+    ) -> Image {
+        self.encode_normals(|pixel| {
+            Some(model.estimate_normal(self.position(pixel)?.as_vec3(), estimator))
+        })
+    }
+
+    /// Build a normal map from world space normals.
+    fn encode_normals(&self, normal: impl Fn(IVec2) -> Option<Vec3>) -> Image {
+        self.layer_image(|pixel| {
+            let n = to_screen(&self.inverse, pixel, normal(pixel)?);
+            Some(encode_normal(n))
+        })
+    }
+
+    /// Export the voxel position layer as a 16-bit RGBA image.
+    ///
+    /// Coordinates are offset by 32768 so that negative positions fit in,
+    /// empty pixels are transparent.
+    pub fn position_image(&self) -> ImageBuffer<Rgba<u16>, Vec<u16>> {
+        self.layer_image(|pixel| {
+            let p = self.position(pixel)? + IVec3::splat(32768);
+            Some(Rgba([p.x as u16, p.y as u16, p.z as u16, 65535]))
+        })
+    }
+
+    fn layer_image<P: image::Pixel>(
+        &self,
+        f: impl Fn(IVec2) -> Option<P>,
+    ) -> ImageBuffer<P, Vec<P::Subpixel>> {
+        let mut ret = ImageBuffer::new(self.width(), self.height());
+        for pixel in self.pixels() {
+            if let Some(p) = f(pixel) {
+                let pos = (pixel - self.rect.min).as_uvec2();
+                ret.put_pixel(pos.x, pos.y, p);
+            }
+        }
+        ret
+    }
+}
+
+impl<T: Clone> RenderBuffers<T> {
+    /// The view pixels in the format of `build_view`.
+    pub fn view(&self) -> HashMap<IVec2, (Vec3, T)> {
+        self.pixels()
+            .map(|pixel| {
+                let i = self.index(pixel).unwrap();
+                let val = self.value[i].clone().unwrap();
+                (pixel, (self.position[i].as_vec3(), val))
+            })
+            .collect()
+    }
+}
+
+impl RenderBuffers<u8> {
+    /// Export the palette index layer as a grayscale image.
+    ///
+    /// Indices are stored off by one like in VOX files, so that empty pixels
+    /// can be zero.
+    pub fn index_image(&self) -> GrayImage {
+        self.layer_image(|pixel| Some(Luma([self.value(pixel)?.saturating_add(1)])))
+    }
+}
+
+/// Encode a unit normal as a normal map color.
+pub fn encode_normal(normal: Vec3) -> Pixel {
+    let c = |x: f32| ((x + 1.0) * 127.5).round().clamp(0.0, 255.0) as u8;
+    Rgba([c(normal.x), c(normal.y), c(normal.z), 255])
+}
+
+/// Rotate a world space `normal` seen at `pixel` into screen space.
+fn to_screen(inverse: &Mat4, pixel: IVec2, normal: Vec3) -> Vec3 {
+    let (origin, dir) = pixel_ray(inverse, pixel);
+    let back = -dir;
+
+    // World space direction of the screen x axis at the pixel, made
+    // perpendicular to the view ray.
+    let right = pixel_ray(inverse, pixel + ivec2(1, 0)).0 - origin;
+    let right = (right - back * right.dot(back)).normalize_or_zero();
+    let up = back.cross(right);
+
+    vec3(normal.dot(right), normal.dot(up), normal.dot(back)).normalize_or_zero()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{build_view, VoxelGrid};
+    use glam::ivec3;
+
+    #[test]
+    fn buffers_match_view() {
+        let mut grid = VoxelGrid::new(IVec3::ZERO, IVec3::splat(4));
+        for z in 0..2 {
+            for y in 0..4 {
+                for x in 0..4 {
+                    grid.set(ivec3(x, y, z), (x + y) as u8);
+                }
+            }
+        }
+
+        for camera in [Camera::TOP_DOWN, Camera::OBLIQUE_NORTH, Camera::ISOMETRIC] {
+            let camera = camera.scaled(2.0);
+            let view = build_view(&grid, &camera);
+            let buffers = render_buffers(&grid, &camera);
+
+            assert_eq!(buffers.view(), view);
+            for (pixel, (pos, val)) in &view {
+                assert_eq!(buffers.value(*pixel), Some(val));
+                assert_eq!(buffers.position(*pixel), Some(pos.as_ivec3()));
+                // The visible face points towards the viewer.
+                let n = buffers.screen_normal(*pixel).unwrap();
+                assert!(n.z > 0.0);
+            }
+        }
+
+        // Top face seen from the top faces straight at the viewer.
+        let camera = Camera::TOP_DOWN;
+        let buffers = render_buffers(&grid, &camera);
+        let pixel = buffers.pixels().next().unwrap();
+        assert_eq!(buffers.normal(pixel), Some(IVec3::Z));
+        assert!((buffers.screen_normal(pixel).unwrap() - Vec3::Z).length() < 1e-4);
+
+        let depth = buffers.depth_image();
+        assert_eq!(depth.get_pixel(0, 0), &Luma([65535]));
+        assert_eq!(buffers.index_image().dimensions(), depth.dimensions());
+    }
+
+    #[test]
+    fn position_image_offset() {
+        // Negative cells need the offset to fit in the channels.
+        let mut grid = VoxelGrid::new(ivec3(-3, -2, -1), IVec3::ONE);
+        grid.set(ivec3(-3, -2, -1), 1u8);
+
+        let buffers = render_buffers(&grid, &Camera::TOP_DOWN);
+        let image = buffers.position_image();
+        assert_eq!(image.dimensions(), (1, 1));
+        assert_eq!(
+            image.get_pixel(0, 0),
+            &Rgba([32768 - 3, 32768 - 2, 32768 - 1, 65535])
+        );
+    }
+}
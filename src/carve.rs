@@ -0,0 +1,530 @@
+//! Build voxel models from sprites by visual hull carving.
+
+use std::{cmp, collections::HashMap};
+
+use anyhow::{bail, Result};
+use dot_vox::DotVoxData;
+use glam::{ivec2, ivec3, vec3, IVec2, IVec3, Mat3, Mat4, Vec3};
+use image::Rgba;
+use itertools::Itertools;
+
+use crate::{build_view, project_with, BoundingBox, Camera, ChunkedVoxels, Image, Palette, Pixel};
+
+/// Transparent color in images we generate.
+pub const DEFAULT_KEY: Pixel = Rgba([0, 0, 0, 0]);
+
+/// Sprite with a marked focus point.
+///
+/// The focus point is the image position where the model space origin is
+/// projected to, so that views from different directions can be lined up.
+#[derive(Clone, Debug)]
+pub struct FocusImage {
+    image: Image,
+    center: IVec2,
+}
+
+impl TryFrom<Image> for FocusImage {
+    type Error = anyhow::Error;
+
+    fn try_from(image: Image) -> Result<Self> {
+        // The source for a focus image must have the lines at x=0 and y=0 be
+        // fully transparent except for a single pixel. The positions of these
+        // pixels will be used to determine the x and y of the center point.
+        // If the pixels aren't found or there is more than one
+        // non-transparent pixel on either line, the construction will fail.
+
+        if image.width() < 2 || image.height() < 2 {
+            bail!("Invalid image size");
+        }
+
+        // Transparent color.
+        let key = *image.get_pixel(0, 0);
+
+        let Ok(Some(x)) = (0..image.width())
+            .filter(|&x| *image.get_pixel(x, 0) != key)
+            .at_most_one()
+        else {
+            bail!("No unique x-focus pixel found");
+        };
+
+        let Ok(Some(y)) = (0..image.height())
+            .filter(|&y| *image.get_pixel(0, y) != key)
+            .at_most_one()
+        else {
+            bail!("No unique y-focus pixel found");
+        };
+
+        // Construct a new image with the lines at x=0 and y=0 cut off.
+        Ok(FocusImage {
+            image: Image::from_fn(image.width() - 1, image.height() - 1, |x, y| {
+                let p = *image.get_pixel(x + 1, y + 1);
+                if p == key {
+                    DEFAULT_KEY
+                } else {
+                    p
+                }
+            }),
+            center: ivec2(x as i32 - 1, y as i32 - 1),
+        })
+    }
+}
+
+impl FocusImage {
+    /// Use an image with a transparent background as a focus image with the
+    /// focus point at `center`.
+    pub fn new(image: Image, center: IVec2) -> Self {
+        FocusImage { image, center }
+    }
+
+    /// Sample the image at image space position `pos` relative to the focus
+    /// point.
+    pub fn sample(&self, pos: IVec2) -> Option<Pixel> {
+        let pos = pos + self.center;
+        if pos.min_element() < 0 {
+            return None;
+        }
+
+        self.image
+            .get_pixel_checked(pos.x as u32, pos.y as u32)
+            .copied()
+            .filter(|&p| p != DEFAULT_KEY)
+    }
+
+    /// Image space bounds of the opaque pixels relative to the focus point.
+    ///
+    /// Returns `None` if the image is fully transparent.
+    pub fn bounds(&self) -> Option<(IVec2, IVec2)> {
+        self.image
+            .enumerate_pixels()
+            .filter(|&(_, _, &p)| p != DEFAULT_KEY)
+            .map(|(x, y, _)| ivec2(x as i32, y as i32) - self.center)
+            .fold(None, |acc, p| match acc {
+                None => Some((p, p + IVec2::ONE)),
+                Some((min, max)) => Some((min.min(p), max.max(p + IVec2::ONE))),
+            })
+    }
+
+    fn safe_get(&self, x: i32, y: i32) -> Pixel {
+        if x < 0 || y < 0 {
+            return DEFAULT_KEY;
+        }
+
+        self.image
+            .get_pixel_checked(x as u32, y as u32)
+            .copied()
+            .unwrap_or(DEFAULT_KEY)
+    }
+
+    pub fn remove_outline(&mut self, outline_color: Pixel) {
+        for y in 0..(self.image.height() as i32) {
+            for x in 0..(self.image.width() as i32) {
+                // Remove pixels of outline color if they're adjacent to image
+                // edge.
+                if self.safe_get(x, y) == outline_color
+                    && (self.safe_get(x - 1, y) == DEFAULT_KEY
+                        || self.safe_get(x + 1, y) == DEFAULT_KEY
+                        || self.safe_get(x, y - 1) == DEFAULT_KEY
+                        || self.safe_get(x, y + 1) == DEFAULT_KEY)
+                {
+                    self.image.put_pixel(x as u32, y as u32, DEFAULT_KEY);
+                }
+            }
+        }
+    }
+}
+
+/// The volume seen by a sprite, extruded along the view direction.
+#[derive(Clone, Debug)]
+pub struct Prism {
+    image: FocusImage,
+    camera: Camera,
+    matrix: Mat4,
+    normal: Vec3,
+}
+
+impl Prism {
+    pub fn new(image: FocusImage, camera: Camera) -> Self {
+        let matrix = Mat4::from(camera);
+        let normal = matrix.inverse().transform_vector3(Vec3::Z).normalize();
+
+        Prism {
+            image,
+            camera,
+            matrix,
+            normal,
+        }
+    }
+
+    /// Sample the sprite color of the cell at `pos`.
+    pub fn sample(&self, pos: IVec3) -> Option<Pixel> {
+        let pixel = project_with(&self.matrix, pos.as_vec3() + Vec3::splat(0.5));
+        self.image.sample(pixel.floor().as_ivec2())
+    }
+
+    /// Model space direction towards the viewer.
+    pub fn normal(&self) -> Vec3 {
+        self.normal
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct VoxelMatch {
+    pub color: Pixel,
+    pub normal: Vec3,
+}
+
+/// Find a box that contains every point that projects inside the opaque
+/// parts of all the views.
+///
+/// Every view bounds two linear functions of the position, and if the views
+/// together constrain all three axes, the position can be solved from them
+/// with the left pseudoinverse. Interval arithmetic through the solution
+/// gives the bounds.
+pub fn view_bounds(views: &[Prism]) -> Result<BoundingBox> {
+    let mut rows = Vec::new();
+    for view in views {
+        if view.camera.is_perspective() {
+            bail!("Can't carve with perspective views");
+        }
+        let Some((min, max)) = view.image.bounds() else {
+            bail!("Empty view image");
+        };
+
+        // Image y axis points down from the screen y axis.
+        let linear = Mat3::from_mat4(view.matrix).transpose();
+        let t = view.matrix.w_axis;
+        rows.push((linear.x_axis, min.x as f32 - t.x, max.x as f32 - t.x));
+        rows.push((linear.y_axis, -max.y as f32 - t.y, -min.y as f32 - t.y));
+    }
+
+    let ata = rows
+        .iter()
+        .fold(Mat3::ZERO, |acc, &(r, _, _)| acc + outer(r, r));
+    if ata.determinant().abs() < 1e-6 {
+        bail!("Views don't constrain the model along all three axes");
+    }
+    let inverse = ata.inverse();
+
+    let (mut min, mut max) = (Vec3::ZERO, Vec3::ZERO);
+    for &(r, lo, hi) in &rows {
+        let b = inverse * r;
+        min += (b * lo).min(b * hi);
+        max += (b * lo).max(b * hi);
+    }
+
+    Ok(BoundingBox::new(min, max))
+}
+
+fn outer(a: Vec3, b: Vec3) -> Mat3 {
+    Mat3::from_cols(a * b.x, a * b.y, a * b.z)
+}
+
+/// Build a voxel model that looks like every view sprite from the view's
+/// direction.
+///
+/// Keeps the cells that are opaque in every view and colors them from the
+/// view that best faces the cell's surface.
+pub fn build_model(views: &[Prism]) -> Result<ChunkedVoxels<Pixel>> {
+    let hull = visual_hull(views)?;
+    Ok(hull
+        .iter()
+        .map(|(pos, matches)| (pos, facing_color(&hull, pos, matches)))
+        .collect())
+}
+
+/// Build a voxel model by space carving.
+///
+/// Starts from the visual hull like `build_model` and then keeps removing
+/// surface cells where the views that can see the cell disagree on its color
+/// by more than `tolerance` in RGB distance, until every visible cell is
+/// consistent. This can carve out concavities that don't show up in the
+/// silhouettes.
+pub fn space_carve(views: &[Prism], tolerance: f32) -> Result<ChunkedVoxels<Pixel>> {
+    let mut hull = visual_hull(views)?;
+
+    loop {
+        let seen = seen_colors(views, &hull);
+
+        let inconsistent: Vec<IVec3> = seen
+            .iter()
+            .filter(|(_, colors)| colors.len() > 1 && spread(colors) > tolerance)
+            .map(|(&pos, _)| pos)
+            .collect();
+
+        if inconsistent.is_empty() {
+            return Ok(hull
+                .iter()
+                .map(|(pos, matches)| {
+                    let color = match seen.get(&pos) {
+                        Some(colors) => central_color(colors),
+                        None => facing_color(&hull, pos, matches),
+                    };
+                    (pos, color)
+                })
+                .collect());
+        }
+
+        for pos in inconsistent {
+            hull.remove(pos);
+        }
+    }
+}
+
+/// Find the cells that are opaque in every view.
+fn visual_hull(views: &[Prism]) -> Result<ChunkedVoxels<Vec<VoxelMatch>>> {
+    if views.is_empty() {
+        bail!("No views to carve from");
+    }
+
+    let bounds = view_bounds(views)?;
+    let (min, max) = (bounds.min.floor().as_ivec3(), bounds.max.ceil().as_ivec3());
+
+    let mut hits: ChunkedVoxels<Vec<VoxelMatch>> = ChunkedVoxels::new();
+    for z in min.z..max.z {
+        for y in min.y..max.y {
+            for x in min.x..max.x {
+                let pos = ivec3(x, y, z);
+
+                let matches: Vec<_> = views
+                    .iter()
+                    .map_while(|view| {
+                        view.sample(pos).map(|color| VoxelMatch {
+                            color,
+                            normal: view.normal(),
+                        })
+                    })
+                    .collect();
+
+                if matches.len() == views.len() {
+                    hits.set(pos, matches);
+                }
+            }
+        }
+    }
+
+    Ok(hits)
+}
+
+/// Pick the color for the cell at `pos` from the view that best faces the
+/// cell's surface.
+fn facing_color(
+    hull: &ChunkedVoxels<Vec<VoxelMatch>>,
+    pos: IVec3,
+    matches: &[VoxelMatch],
+) -> Pixel {
+    // Crude voxel surface normal based on open faces.
+    let normal = [
+        IVec3::X,
+        IVec3::NEG_X,
+        IVec3::Y,
+        IVec3::NEG_Y,
+        IVec3::Z,
+        IVec3::NEG_Z,
+    ]
+    .into_iter()
+    .filter(|&d| hull.get(pos + d).is_none())
+    .fold(Vec3::ZERO, |a, d| a + d.as_vec3())
+    .normalize_or_zero();
+
+    // Find the match whose normal is closest to the surface normal.
+    matches
+        .iter()
+        .min_by_key(|m| {
+            // Reverse the order so the best match is the one with the
+            // highest dot product.
+            //
+            // HACK: Bits gets us an ordering-preserving Ord-able value from
+            // non-negative f32, so shift the range up from [-1, 1].
+            cmp::Reverse((m.normal.dot(normal) + 1.0).to_bits())
+        })
+        .map(|m| m.color)
+        .unwrap()
+}
+
+/// Collect the colors that each view shows for the cells it can see.
+///
+/// Every view contributes its average color over the pixels that see the
+/// cell. Transparent pixels are left to the visual hull and ignored.
+fn seen_colors(
+    views: &[Prism],
+    model: &ChunkedVoxels<Vec<VoxelMatch>>,
+) -> HashMap<IVec3, Vec<Vec3>> {
+    // Trace against plain occupancy so the match lists don't get cloned on
+    // every sample.
+    let model: ChunkedVoxels<()> = model.iter().map(|(pos, _)| (pos, ())).collect();
+
+    let mut ret: HashMap<IVec3, Vec<Vec3>> = HashMap::new();
+
+    for view in views {
+        let mut sums: HashMap<IVec3, (Vec3, f32)> = HashMap::new();
+        for (pixel, (pos, _)) in build_view(&model, &view.camera) {
+            if let Some(color) = view.image.sample(pixel) {
+                let sum = sums.entry(pos.as_ivec3()).or_default();
+                sum.0 += to_vec3(color);
+                sum.1 += 1.0;
+            }
+        }
+
+        for (pos, (sum, n)) in sums {
+            ret.entry(pos).or_default().push(sum / n);
+        }
+    }
+
+    ret
+}
+
+fn to_vec3(color: Pixel) -> Vec3 {
+    vec3(color[0] as f32, color[1] as f32, color[2] as f32)
+}
+
+/// Largest distance of a color from the mean color.
+fn spread(colors: &[Vec3]) -> f32 {
+    let mean = colors.iter().sum::<Vec3>() / colors.len() as f32;
+    colors.iter().map(|c| c.distance(mean)).fold(0.0, f32::max)
+}
+
+/// Pick the color closest to the mean of `colors`.
+///
+/// Using one of the seen colors instead of the mean keeps the model closer
+/// to the sprite palette.
+fn central_color(colors: &[Vec3]) -> Pixel {
+    let mean = colors.iter().sum::<Vec3>() / colors.len() as f32;
+    let c = colors
+        .iter()
+        .min_by(|a, b| a.distance(mean).total_cmp(&b.distance(mean)))
+        .unwrap()
+        .round();
+    Rgba([c.x as u8, c.y as u8, c.z as u8, 255])
+}
+
+/// Convert a model into a VOX file.
+///
+/// Model colors are mapped through `palette`, quantizing them if there are
+/// too many, and optionally dithered. The scene graph keeps the model in
+/// place, so the focus point of the views stays at the world origin. Models
+/// larger than the 256 cells a VOX model can span are split into several.
+pub fn to_vox(
+    model: &ChunkedVoxels<Pixel>,
+    palette: &mut Palette,
+    dither: bool,
+) -> Result<DotVoxData> {
+    if model.is_empty() {
+        bail!("Empty model");
+    }
+
+    let mut counts = HashMap::new();
+    for (_, &color) in model.iter() {
+        *counts.entry(color).or_default() += 1;
+    }
+    palette.fit(&counts);
+
+    let indices: ChunkedVoxels<u8> = model
+        .iter()
+        .filter_map(|(pos, &color)| {
+            let i = if dither {
+                palette.index_dithered(color, pos)
+            } else {
+                palette.index(color)
+            };
+            i.map(|i| (pos, i))
+        })
+        .collect();
+
+    let mut ret = indices.to_vox();
+    ret.palette.clear();
+    palette.apply(&mut ret);
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Body, Policy};
+
+    /// Make a focus image with a centered opaque square.
+    fn square(radius: u32) -> FocusImage {
+        let size = radius * 2 + 3;
+        let image = Image::from_fn(size, size, |x, y| {
+            let c = radius + 1;
+            if (x == c && y == 0) || (x == 0 && y == c) {
+                // Focus markers.
+                Rgba([255, 0, 0, 255])
+            } else if x > 0 && y > 0 && x.abs_diff(c) <= radius && y.abs_diff(c) <= radius {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        });
+        FocusImage::try_from(image).unwrap()
+    }
+
+    #[test]
+    fn carve_box() {
+        let views: Vec<_> = [Camera::SIDE, Camera::SIDE.rotated(90.0), Camera::TOP_DOWN]
+            .into_iter()
+            .map(|camera| Prism::new(square(3), camera))
+            .collect();
+
+        let model = build_model(&views).unwrap();
+        let aabb = model.bounding_box();
+        assert!(aabb.contains(Vec3::ZERO));
+        // Seven pixels wide views of the cube.
+        assert_eq!(model.iter().count(), 7 * 7 * 7);
+
+        let vox = to_vox(&model, &mut Palette::new(Policy::Exact), false).unwrap();
+        assert_eq!(vox.models[0].size, dot_vox::Size { x: 7, y: 7, z: 7 });
+        // The model stays in place around the origin.
+        let scene = crate::Scene::try_from(&vox).unwrap();
+        for (pos, _) in model.iter() {
+            assert!(scene.sample(pos.as_vec3()).is_some());
+        }
+
+        // A single view can't bound the model.
+        assert!(build_model(&views[..1]).is_err());
+    }
+
+    #[test]
+    fn space_carve_pit() {
+        // A cube with a pit in the middle of the top face that doesn't show
+        // in any silhouette.
+        let pit = ivec3(2, 2, 4);
+        let truth: ChunkedVoxels<Pixel> = (0..125)
+            .map(|i| ivec3(i % 5, i / 5 % 5, i / 25))
+            .filter(|&p| p != pit)
+            .map(|p| {
+                (
+                    p,
+                    Rgba([p.x as u8 * 60, p.y as u8 * 60, p.z as u8 * 60, 255]),
+                )
+            })
+            .collect();
+
+        let views: Vec<_> = (0..4)
+            .map(|i| Camera::ISOMETRIC.rotated(i as f32 * 90.0).scaled(2.0))
+            .chain([Camera::TOP_DOWN.scaled(2.0)])
+            .map(|camera| {
+                // Render the sprite for the view.
+                let view = build_view(&truth, &camera);
+                let min = view.keys().fold(IVec2::MAX, |a, &b| a.min(b));
+                let max = view.keys().fold(IVec2::MIN, |a, &b| a.max(b));
+                let size = (max - min + IVec2::ONE).as_uvec2();
+                let mut image = Image::from_pixel(size.x, size.y, DEFAULT_KEY);
+                for (pixel, (_, color)) in view {
+                    let pixel = (pixel - min).as_uvec2();
+                    image.put_pixel(pixel.x, pixel.y, color);
+                }
+                Prism::new(FocusImage::new(image, -min), camera)
+            })
+            .collect();
+
+        let hull = build_model(&views).unwrap();
+        assert!(hull.get(pit).is_some());
+
+        let carved = space_carve(&views, 1.0).unwrap();
+        assert!(carved.get(pit).is_none());
+        for (pos, _) in truth.iter() {
+            assert!(carved.get(pos).is_some());
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+
+use dot_vox::{DotVoxData, SceneNode};
+use glam::{ivec3, IVec3, Vec3};
+
+use crate::{Body, BoundingBox, PALETTE_SLOTS};
+
+/// Width of a chunk cube in cells. Must be a power of two.
+pub const CHUNK_SIZE: i32 = 16;
+
+const CHUNK_LEN: usize = (CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+#[derive(Clone, Debug)]
+struct Chunk<T> {
+    /// Number of occupied cells, empty chunks get removed.
+    count: usize,
+    cells: Vec<Option<T>>,
+}
+
+impl<T> Default for Chunk<T> {
+    fn default() -> Self {
+        Chunk {
+            count: 0,
+            cells: std::iter::repeat_with(|| None).take(CHUNK_LEN).collect(),
+        }
+    }
+}
+
+/// Sparse unbounded voxel volume.
+///
+/// Cells are stored in fixed size chunks that are only allocated when they
+/// have content, so mostly empty scenes stay cheap and coordinates can go
+/// negative.
+#[derive(Clone, Debug)]
+pub struct ChunkedVoxels<T> {
+    chunks: HashMap<IVec3, Chunk<T>>,
+}
+
+impl<T> Default for ChunkedVoxels<T> {
+    fn default() -> Self {
+        ChunkedVoxels {
+            chunks: Default::default(),
+        }
+    }
+}
+
+/// Split a cell position into chunk coordinates and index within the chunk.
+fn split(pos: IVec3) -> (IVec3, usize) {
+    // Arithmetic shift rounds towards negative infinity, so this works for
+    // negative coordinates.
+    let shift = CHUNK_SIZE.trailing_zeros();
+    let chunk = ivec3(pos.x >> shift, pos.y >> shift, pos.z >> shift);
+    let local = pos & IVec3::splat(CHUNK_SIZE - 1);
+    let idx = local.x + local.y * CHUNK_SIZE + local.z * CHUNK_SIZE * CHUNK_SIZE;
+
+    (chunk, idx as usize)
+}
+
+fn join(chunk: IVec3, idx: usize) -> IVec3 {
+    let idx = idx as i32;
+    chunk * CHUNK_SIZE
+        + ivec3(
+            idx % CHUNK_SIZE,
+            idx / CHUNK_SIZE % CHUNK_SIZE,
+            idx / (CHUNK_SIZE * CHUNK_SIZE),
+        )
+}
+
+impl<T> ChunkedVoxels<T> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Number of allocated chunks.
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    pub fn get(&self, pos: IVec3) -> Option<&T> {
+        let (chunk, idx) = split(pos);
+        self.chunks
+            .get(&chunk)
+            .and_then(|chunk| chunk.cells[idx].as_ref())
+    }
+
+    /// Set a cell and return its previous value.
+    pub fn set(&mut self, pos: IVec3, value: T) -> Option<T> {
+        let (chunk, idx) = split(pos);
+        let chunk = self.chunks.entry(chunk).or_default();
+        let ret = chunk.cells[idx].replace(value);
+        if ret.is_none() {
+            chunk.count += 1;
+        }
+        ret
+    }
+
+    pub fn remove(&mut self, pos: IVec3) -> Option<T> {
+        let (chunk_pos, idx) = split(pos);
+        let chunk = self.chunks.get_mut(&chunk_pos)?;
+        let ret = chunk.cells[idx].take();
+        if ret.is_some() {
+            chunk.count -= 1;
+            if chunk.count == 0 {
+                self.chunks.remove(&chunk_pos);
+            }
+        }
+        ret
+    }
+
+    /// Iterate the occupied cells in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (IVec3, &T)> + '_ {
+        self.chunks.iter().flat_map(|(&chunk, cells)| {
+            cells
+                .cells
+                .iter()
+                .enumerate()
+                .filter_map(move |(idx, v)| v.as_ref().map(|v| (join(chunk, idx), v)))
+        })
+    }
+}
+
+impl<T> FromIterator<(IVec3, T)> for ChunkedVoxels<T> {
+    fn from_iter<I: IntoIterator<Item = (IVec3, T)>>(iter: I) -> Self {
+        let mut ret = ChunkedVoxels::new();
+        for (pos, value) in iter {
+            ret.set(pos, value);
+        }
+        ret
+    }
+}
+
+impl<T: Clone> Body for ChunkedVoxels<T> {
+    type Value = T;
+
+    fn sample(&self, pos: Vec3) -> Option<Self::Value> {
+        self.get(pos.floor().as_ivec3()).cloned()
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        if self.chunks.is_empty() {
+            return Default::default();
+        }
+
+        let (min, max) = self
+            .chunks
+            .keys()
+            .fold((IVec3::MAX, IVec3::MIN), |(min, max), &chunk| {
+                (min.min(chunk), max.max(chunk))
+            });
+
+        BoundingBox::new(
+            (min * CHUNK_SIZE).as_vec3(),
+            ((max + IVec3::ONE) * CHUNK_SIZE).as_vec3(),
+        )
+    }
+}
+
+impl From<&dot_vox::Model> for ChunkedVoxels<u8> {
+    fn from(model: &dot_vox::Model) -> Self {
+        model
+            .voxels
+            .iter()
+            .map(|v| (ivec3(v.x as i32, v.y as i32, v.z as i32), v.i))
+            .collect()
+    }
+}
+
+/// Largest model extent a VOX file can store.
+const VOX_MODEL_SIZE: i32 = 256;
+
+impl ChunkedVoxels<u8> {
+    /// Convert into a VOX file with the palette indices of the cells.
+    ///
+    /// The volume is split into models of at most 256 cells per side, which
+    /// the scene graph places so that `Scene` reads them back at the same
+    /// world positions. The file gets the default palette.
+    ///
+    /// # Panics
+    ///
+    /// If a cell holds palette index 255, which VOX files can't store.
+    pub fn to_vox(&self) -> DotVoxData {
+        // Line up the blocks with the volume, so that volumes that fit in a
+        // single model become one.
+        let origin = self.iter().fold(IVec3::MAX, |min, (pos, _)| min.min(pos));
+
+        let mut blocks: HashMap<IVec3, Vec<(IVec3, u8)>> = HashMap::new();
+        for (pos, &i) in self.iter() {
+            assert!(
+                (i as usize) < PALETTE_SLOTS,
+                "Palette index {i} of voxel {pos} outside VOX palette range"
+            );
+            blocks
+                .entry((pos - origin).div_euclid(IVec3::splat(VOX_MODEL_SIZE)))
+                .or_default()
+                .push((pos, i));
+        }
+        let mut blocks: Vec<_> = blocks.into_iter().collect();
+        blocks.sort_by_key(|(block, _)| block.to_array());
+
+        let mut ret = DotVoxData {
+            version: 150,
+            models: Vec::new(),
+            palette: dot_vox::DEFAULT_PALETTE.to_vec(),
+            materials: Vec::new(),
+            scenes: vec![
+                SceneNode::Transform {
+                    attributes: Default::default(),
+                    frames: vec![dot_vox::Frame::new(Default::default())],
+                    child: 1,
+                    layer_id: 0,
+                },
+                SceneNode::Group {
+                    attributes: Default::default(),
+                    children: Vec::new(),
+                },
+            ],
+            layers: Vec::new(),
+        };
+
+        for (_, cells) in blocks {
+            let (min, max) = cells
+                .iter()
+                .fold((IVec3::MAX, IVec3::MIN), |(min, max), &(p, _)| {
+                    (min.min(p), max.max(p))
+                });
+            let size = max - min + IVec3::ONE;
+
+            let voxels = cells
+                .iter()
+                .map(|&(p, i)| {
+                    let p = p - min;
+                    dot_vox::Voxel {
+                        x: p.x as u8,
+                        y: p.y as u8,
+                        z: p.z as u8,
+                        i,
+                    }
+                })
+                .collect();
+
+            let model_id = ret.models.len() as u32;
+            ret.models.push(dot_vox::Model {
+                size: dot_vox::Size {
+                    x: size.x as u32,
+                    y: size.y as u32,
+                    z: size.z as u32,
+                },
+                voxels,
+            });
+
+            // MagicaVoxel places models by their center cell.
+            let t = min + size / 2;
+            let node = ret.scenes.len() as u32;
+            ret.scenes.push(SceneNode::Transform {
+                attributes: Default::default(),
+                frames: vec![dot_vox::Frame::new(
+                    [("_t".to_string(), format!("{} {} {}", t.x, t.y, t.z))]
+                        .into_iter()
+                        .collect(),
+                )],
+                child: node + 1,
+                layer_id: 0,
+            });
+            ret.scenes.push(SceneNode::Shape {
+                attributes: Default::default(),
+                models: vec![dot_vox::ShapeModel {
+                    model_id,
+                    attributes: Default::default(),
+                }],
+            });
+            if let SceneNode::Group { children, .. } = &mut ret.scenes[1] {
+                children.push(node);
+            }
+        }
+
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::vec3;
+
+    #[test]
+    fn chunked_negative_coordinates() {
+        let mut voxels = ChunkedVoxels::new();
+        voxels.set(ivec3(-1, -1, -1), 1);
+        voxels.set(ivec3(0, 0, 0), 2);
+        voxels.set(ivec3(-17, 300, 5), 3);
+
+        assert_eq!(voxels.chunk_count(), 3);
+        assert_eq!(voxels.sample(vec3(-0.5, -0.5, -0.5)), Some(1));
+        assert_eq!(voxels.sample(vec3(0.5, 0.5, 0.5)), Some(2));
+        assert_eq!(voxels.get(ivec3(-17, 300, 5)), Some(&3));
+        assert_eq!(voxels.get(ivec3(-16, 300, 5)), None);
+
+        let mut cells: Vec<_> = voxels.iter().map(|(p, &v)| (p, v)).collect();
+        cells.sort_by_key(|&(_, v)| v);
+        assert_eq!(
+            cells,
+            vec![
+                (ivec3(-1, -1, -1), 1),
+                (ivec3(0, 0, 0), 2),
+                (ivec3(-17, 300, 5), 3)
+            ]
+        );
+
+        let bounds = voxels.bounding_box();
+        assert_eq!(bounds.min, vec3(-32.0, -16.0, -16.0));
+        assert_eq!(bounds.max, vec3(16.0, 304.0, 16.0));
+
+        // Emptied chunks are dropped and stop counting towards bounds.
+        voxels.remove(ivec3(-17, 300, 5));
+        assert_eq!(voxels.chunk_count(), 2);
+        assert_eq!(voxels.bounding_box().max, vec3(16.0, 16.0, 16.0));
+    }
+
+    #[test]
+    fn chunked_to_vox() {
+        let voxels: ChunkedVoxels<u8> = [
+            (ivec3(-1, -1, -1), 1),
+            (ivec3(0, 0, 0), 2),
+            (ivec3(-17, 300, 5), 3),
+            (ivec3(255, 0, 1), 4),
+        ]
+        .into_iter()
+        .collect();
+
+        let vox = voxels.to_vox();
+        // Too far apart for a single VOX model.
+        assert_eq!(vox.models.len(), 3);
+        assert!(vox
+            .models
+            .iter()
+            .all(|m| m.size.x.max(m.size.y).max(m.size.z) <= 256));
+
+        let scene = crate::Scene::try_from(&vox).unwrap();
+        for (pos, &i) in voxels.iter() {
+            assert_eq!(scene.sample(pos.as_vec3()), Some(i));
+        }
+        assert_eq!(scene.sample(vec3(1.5, 0.5, 0.5)), None);
+
+        // Small volume around the origin stays in one piece.
+        let voxels: ChunkedVoxels<u8> = [(ivec3(-3, -3, -3), 1), (ivec3(3, 3, 3), 2)]
+            .into_iter()
+            .collect();
+        assert_eq!(voxels.to_vox().models.len(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn chunked_to_vox_palette_range() {
+        let voxels: ChunkedVoxels<u8> = [(ivec3(0, 0, 0), 255)].into_iter().collect();
+        voxels.to_vox();
+    }
+}
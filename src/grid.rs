@@ -0,0 +1,162 @@
+use glam::{ivec3, IVec3, Vec3};
+
+use crate::{Body, BoundingBox};
+
+/// Dense voxel volume with constant-time lookups.
+///
+/// Covers the cells from `origin` (inclusive) to `origin + size` (exclusive).
+#[derive(Clone, Debug)]
+pub struct VoxelGrid<T> {
+    origin: IVec3,
+    size: IVec3,
+    data: Vec<Option<T>>,
+}
+
+impl<T> VoxelGrid<T> {
+    /// Create an empty grid.
+    pub fn new(origin: IVec3, size: IVec3) -> Self {
+        assert!(size.min_element() >= 0, "Grid size must be non-negative");
+        let len = size.x as usize * size.y as usize * size.z as usize;
+
+        VoxelGrid {
+            origin,
+            size,
+            data: std::iter::repeat_with(|| None).take(len).collect(),
+        }
+    }
+
+    pub fn origin(&self) -> IVec3 {
+        self.origin
+    }
+
+    pub fn size(&self) -> IVec3 {
+        self.size
+    }
+
+    fn index(&self, pos: IVec3) -> Option<usize> {
+        let pos = pos - self.origin;
+        if pos.min_element() < 0 || pos.cmpge(self.size).any() {
+            return None;
+        }
+
+        Some(
+            pos.x as usize
+                + pos.y as usize * self.size.x as usize
+                + pos.z as usize * self.size.x as usize * self.size.y as usize,
+        )
+    }
+
+    fn pos(&self, idx: usize) -> IVec3 {
+        let (w, h) = (self.size.x as usize, self.size.y as usize);
+        self.origin
+            + ivec3(
+                (idx % w) as i32,
+                (idx / w % h) as i32,
+                (idx / (w * h)) as i32,
+            )
+    }
+
+    pub fn get(&self, pos: IVec3) -> Option<&T> {
+        self.index(pos).and_then(|idx| self.data[idx].as_ref())
+    }
+
+    /// Set a cell and return its previous value.
+    ///
+    /// Panics if `pos` is outside the grid.
+    pub fn set(&mut self, pos: IVec3, value: T) -> Option<T> {
+        let idx = self
+            .index(pos)
+            .unwrap_or_else(|| panic!("Position {pos} outside voxel grid"));
+        self.data[idx].replace(value)
+    }
+
+    pub fn remove(&mut self, pos: IVec3) -> Option<T> {
+        self.index(pos).and_then(|idx| self.data[idx].take())
+    }
+
+    /// Iterate the occupied cells of the grid.
+    pub fn iter(&self) -> impl Iterator<Item = (IVec3, &T)> + '_ {
+        self.data
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, v)| v.as_ref().map(|v| (self.pos(idx), v)))
+    }
+}
+
+impl<T: Clone> Body for VoxelGrid<T> {
+    type Value = T;
+
+    fn sample(&self, pos: Vec3) -> Option<Self::Value> {
+        self.get(pos.floor().as_ivec3()).cloned()
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        BoundingBox::new(self.origin.as_vec3(), (self.origin + self.size).as_vec3())
+    }
+}
+
+impl From<&dot_vox::Model> for VoxelGrid<u8> {
+    fn from(model: &dot_vox::Model) -> Self {
+        let size = ivec3(
+            model.size.x as i32,
+            model.size.y as i32,
+            model.size.z as i32,
+        );
+        let mut ret = VoxelGrid::new(IVec3::ZERO, size);
+        for voxel in &model.voxels {
+            let pos = ivec3(voxel.x as i32, voxel.y as i32, voxel.z as i32);
+            // Be lenient with voxels that are outside the declared model
+            // size.
+            if ret.index(pos).is_some() {
+                ret.set(pos, voxel.i);
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::vec3;
+
+    #[test]
+    fn grid_from_model() {
+        let model = dot_vox::Model {
+            size: dot_vox::Size { x: 3, y: 4, z: 5 },
+            voxels: vec![
+                dot_vox::Voxel {
+                    x: 0,
+                    y: 0,
+                    z: 0,
+                    i: 1,
+                },
+                dot_vox::Voxel {
+                    x: 2,
+                    y: 3,
+                    z: 4,
+                    i: 2,
+                },
+                dot_vox::Voxel {
+                    x: 1,
+                    y: 2,
+                    z: 3,
+                    i: 3,
+                },
+            ],
+        };
+        let grid = VoxelGrid::from(&model);
+
+        for z in -1..6 {
+            for y in -1..5 {
+                for x in -1..4 {
+                    let pos = vec3(x as f32, y as f32, z as f32);
+                    assert_eq!(grid.sample(pos), model.sample(pos));
+                }
+            }
+        }
+
+        assert_eq!(grid.iter().count(), 3);
+        assert_eq!(grid.get(ivec3(1, 2, 3)), Some(&3));
+    }
+}
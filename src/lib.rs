@@ -1,32 +1,192 @@
 use std::collections::HashMap;
 
 use dot_vox::DotVoxData;
-use glam::{ivec2, ivec3, vec2, vec3, IVec2, IVec3, Mat4, Vec2, Vec3};
+use glam::{ivec2, ivec3, vec2, vec3, vec4, IVec2, IVec3, Mat4, Vec2, Vec3};
 use image::{ImageBuffer, Rgba};
 
+mod brickmap;
+pub use brickmap::{Brickmap, BRICK_SIZE};
+
+mod buffers;
+#[cfg(feature = "rayon")]
+pub use buffers::render_buffers_par;
+pub use buffers::{encode_normal, render_buffers, RenderBuffers};
+
+pub mod carve;
+
+mod chunked;
+pub use chunked::{ChunkedVoxels, CHUNK_SIZE};
+
+mod grid;
+pub use grid::VoxelGrid;
+
+mod lighting;
+pub use lighting::{drop_shadow, in_shadow, openness, Light, Lighting};
+
+mod normals;
+pub use normals::{gradient_normal, plane_fit_normal, NormalEstimator};
+
+mod outline;
+pub use outline::{Connectivity, Outline, OutlineColor};
+
+mod palette;
+pub use palette::{oklab, Palette, Policy, PALETTE_SLOTS};
+
+mod quantize;
+pub use quantize::{bayer_threshold, median_cut};
+
+mod render;
+pub use render::{render, render_view, Anchor, Canvas, Padding, RenderOptions};
+
+mod scene;
+pub use scene::{keyframes, Instance, Placement, Scene};
+
 pub type Pixel = Rgba<u8>;
 pub type Image = ImageBuffer<Pixel, Vec<u8>>;
 
+/// A grid cell visited by a ray.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TraceStep {
+    /// Integer coordinates of the unit cell, the cell covers `cell` to `cell
+    /// + 1`.
+    pub cell: IVec3,
+    /// Outward normal of the face the ray entered the cell through. Zero for
+    /// the cell the ray starts in.
+    pub normal: IVec3,
+    /// Ray parameter where the ray enters the cell, the entry point is
+    /// `origin + t * dir`.
+    pub t: f32,
+}
+
 /// Trace voxel cells a ray will pass through.
-pub fn trace(origin: Vec3, dir: Vec3) -> impl Iterator<Item = Vec3> {
-    // DDA algorithm.
-
-    // XXX: This misses some side voxels but should always draw a solid line.
-    assert!(
-        dir.length_squared() > 0.00001,
-        "Direction vector must be non-zero"
-    );
-
-    // Normalize longest axis to unit length.
-    let scale = 1.0 / dir.abs().max_element();
-    let dir = scale * dir;
-
-    let mut pos = origin;
-    std::iter::from_fn(move || {
-        let cell = pos.round();
-        pos += dir;
-        Some(cell)
-    })
+///
+/// Visits every cell the ray crosses in order, with no gaps or skipped
+/// corners.
+pub fn trace(origin: Vec3, dir: Vec3) -> impl Iterator<Item = TraceStep> {
+    Traversal::new(origin, dir, 1)
+}
+
+/// Grid traversal state for `trace`.
+///
+/// Amanatides & Woo, "A Fast Voxel Traversal Algorithm for Ray Tracing".
+/// Boundary crossings are always computed from the cell coordinates instead
+/// of being accumulated, so traversals of the same ray over grids of
+/// different cell widths agree exactly on where they cross shared
+/// boundaries.
+#[derive(Clone, Debug)]
+pub(crate) struct Traversal {
+    origin: Vec3,
+    dir: Vec3,
+    /// Width of a grid cell.
+    width: i32,
+    step: IVec3,
+    current: TraceStep,
+    /// Ray parameters for crossing out of the current cell on each axis.
+    t_max: Vec3,
+}
+
+impl Traversal {
+    pub fn new(origin: Vec3, dir: Vec3, width: i32) -> Self {
+        let cell = origin.floor().as_ivec3().div_euclid(IVec3::splat(width));
+        Self::from_step(
+            origin,
+            dir,
+            width,
+            TraceStep {
+                cell,
+                normal: IVec3::ZERO,
+                t: 0.0,
+            },
+        )
+    }
+
+    /// Resume traversal of a ray from a given step.
+    pub fn from_step(origin: Vec3, dir: Vec3, width: i32, current: TraceStep) -> Self {
+        assert!(
+            dir.length_squared() > 0.00001,
+            "Direction vector must be non-zero"
+        );
+
+        let step = ivec3(
+            dir.x.signum() as i32 * (dir.x != 0.0) as i32,
+            dir.y.signum() as i32 * (dir.y != 0.0) as i32,
+            dir.z.signum() as i32 * (dir.z != 0.0) as i32,
+        );
+
+        let mut ret = Traversal {
+            origin,
+            dir,
+            width,
+            step,
+            current,
+            t_max: Vec3::ZERO,
+        };
+        for axis in 0..3 {
+            ret.t_max[axis] = ret.exit_t(current.cell[axis], axis);
+        }
+        ret
+    }
+
+    /// Ray parameter where the ray leaves the cell at coordinate `cell` on
+    /// `axis`.
+    pub fn exit_t(&self, cell: i32, axis: usize) -> f32 {
+        let boundary = match self.step[axis] {
+            1 => (cell + 1) * self.width,
+            -1 => cell * self.width,
+            _ => return f32::INFINITY,
+        };
+        (boundary as f32 - self.origin[axis]) / self.dir[axis]
+    }
+
+    /// Axis whose boundary gets crossed next.
+    ///
+    /// Ties go to the lowest axis, which is what makes the crossing order
+    /// well-defined when the ray hits cell edges or corners exactly.
+    fn next_axis(&self) -> usize {
+        let t = self.t_max;
+        if t.x <= t.y && t.x <= t.z {
+            0
+        } else if t.y <= t.z {
+            1
+        } else {
+            2
+        }
+    }
+
+    pub fn origin(&self) -> Vec3 {
+        self.origin
+    }
+
+    pub fn dir(&self) -> Vec3 {
+        self.dir
+    }
+
+    pub fn step(&self) -> IVec3 {
+        self.step
+    }
+}
+
+impl Iterator for Traversal {
+    type Item = TraceStep;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ret = self.current;
+
+        let axis = self.next_axis();
+        let mut normal = IVec3::ZERO;
+        normal[axis] = -self.step[axis];
+        let mut cell = ret.cell;
+        cell[axis] += self.step[axis];
+
+        self.current = TraceStep {
+            cell,
+            normal,
+            t: self.t_max[axis],
+        };
+        self.t_max[axis] = self.exit_t(cell[axis], axis);
+
+        Some(ret)
+    }
 }
 
 /// A volumetric object of some sort.
@@ -39,6 +199,16 @@ pub trait Body {
         Default::default()
     }
 
+    /// Find the first occupied cell along a ray.
+    ///
+    /// The ray is traced from `origin` until parameter `len`. Bodies with
+    /// acceleration structures can override this to skip empty space.
+    fn cast(&self, origin: Vec3, dir: Vec3, len: f32) -> Option<(TraceStep, Self::Value)> {
+        trace(origin, dir)
+            .take_while(|step| step.t < len)
+            .find_map(|step| self.sample(step.cell.as_vec3()).map(|val| (step, val)))
+    }
+
     fn normal(&self, pos: Vec3) -> Vec3 {
         let mut n = Vec3::ZERO;
         for x in -1..=1i32 {
@@ -61,6 +231,15 @@ pub trait Body {
             Vec3::ZERO
         }
     }
+
+    /// Estimate the surface normal of the voxel at `pos` with `estimator`.
+    fn estimate_normal(&self, pos: Vec3, estimator: NormalEstimator) -> Vec3 {
+        match estimator {
+            NormalEstimator::Neighbors => self.normal(pos),
+            NormalEstimator::Gradient(radius) => gradient_normal(self, pos, radius),
+            NormalEstimator::PlaneFit(radius) => plane_fit_normal(self, pos, radius),
+        }
+    }
 }
 
 impl Body for dot_vox::Model {
@@ -93,45 +272,178 @@ impl Body for dot_vox::Model {
                     (Vec3::min(min, voxel), Vec3::max(max, voxel))
                 });
 
-        BoundingBox::new(min, max)
+        // Voxel cells extend one unit from their coordinates.
+        BoundingBox::new(min, max + Vec3::ONE)
     }
 }
 
-#[derive(Copy, Clone, Debug)]
-pub enum Camera {
-    ObliqueNorth,
-    ObliqueEast,
-    ObliqueSouth,
-    ObliqueWest,
+/// Height of the vertical field of view of a perspective camera in pixels at
+/// scale 1.
+pub const PERSPECTIVE_HEIGHT: f32 = 256.0;
+
+/// Closest distance to the eye of a perspective camera that can be seen.
+const NEAR_PLANE: f32 = 0.01;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+    /// Parallel view rays, for pixel art.
+    Orthographic,
+    /// View rays spread out from the eye.
+    Perspective {
+        /// Eye position in model space.
+        eye: Vec3,
+        /// Vertical field of view in degrees.
+        fov: f32,
+    },
+}
+
+/// View of a model.
+///
+/// The view is built by turning the model around the vertical axis by `yaw`,
+/// tilting it towards the viewer by `pitch`, shearing the vertical axis by
+/// `shear` and finally scaling everything by `scale`. View space has x
+/// pointing right, y pointing up and the viewer looking towards negative z.
+///
+/// With perspective projection, the view looks from the eye position in the
+/// direction given by yaw and pitch, and shear is ignored.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Camera {
+    /// Rotation around the vertical axis in degrees.
+    pub yaw: f32,
+    /// Tilt in degrees, 0 looks straight down and 90 looks horizontally at
+    /// the negative y side of the model.
+    pub pitch: f32,
+    /// Oblique shear, how far up and to the left the view shifts per unit
+    /// of depth.
+    pub shear: f32,
+    /// Pixels per voxel.
+    pub scale: f32,
+    pub projection: Projection,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera::OBLIQUE_NORTH
+    }
 }
 
-use Camera::*;
+impl Camera {
+    /// Top-down oblique projection where the height of the model shows up
+    /// as a diagonal shift.
+    pub const OBLIQUE_NORTH: Camera = Camera {
+        yaw: 0.0,
+        pitch: 0.0,
+        shear: 0.5,
+        scale: 1.0,
+        projection: Projection::Orthographic,
+    };
+    pub const OBLIQUE_EAST: Camera = Camera::OBLIQUE_NORTH.rotated(90.0);
+    pub const OBLIQUE_SOUTH: Camera = Camera::OBLIQUE_NORTH.rotated(180.0);
+    pub const OBLIQUE_WEST: Camera = Camera::OBLIQUE_NORTH.rotated(270.0);
+
+    /// True isometric pixel art projection, where the ground plane has a 2:1
+    /// width to height ratio.
+    pub const ISOMETRIC: Camera = Camera {
+        yaw: 45.0,
+        pitch: 60.0,
+        shear: 0.0,
+        scale: 1.0,
+        projection: Projection::Orthographic,
+    };
+
+    /// Straight down view showing only the top of the model.
+    pub const TOP_DOWN: Camera = Camera {
+        yaw: 0.0,
+        pitch: 0.0,
+        shear: 0.0,
+        scale: 1.0,
+        projection: Projection::Orthographic,
+    };
+
+    /// Horizontal view of the front of the model.
+    pub const SIDE: Camera = Camera {
+        yaw: 0.0,
+        pitch: 90.0,
+        shear: 0.0,
+        scale: 1.0,
+        projection: Projection::Orthographic,
+    };
+
+    /// Return the camera turned further around the vertical axis.
+    pub const fn rotated(self, degrees: f32) -> Camera {
+        Camera {
+            yaw: self.yaw + degrees,
+            ..self
+        }
+    }
+
+    pub const fn scaled(self, scale: f32) -> Camera {
+        Camera {
+            scale: self.scale * scale,
+            ..self
+        }
+    }
+
+    /// Return a perspective camera with the same view direction and the eye
+    /// placed so that the whole bounding box fits in the field of view.
+    pub fn framing(self, aabb: &BoundingBox, fov: f32) -> Camera {
+        let center = (aabb.min + aabb.max) / 2.0;
+        let radius = (aabb.max - aabb.min).length() / 2.0;
+        let distance = radius / (fov.to_radians() / 2.0).sin();
+
+        Camera {
+            projection: Projection::Perspective {
+                eye: center + distance * self.backward(),
+                fov,
+            },
+            ..self
+        }
+    }
+
+    /// Return whether view rays spread out from an eye point.
+    pub fn is_perspective(&self) -> bool {
+        matches!(self.projection, Projection::Perspective { .. })
+    }
+
+    /// Model space direction pointing from the model towards the viewer.
+    pub fn backward(&self) -> Vec3 {
+        self.rotation().inverse().transform_vector3(Vec3::Z)
+    }
+
+    fn rotation(&self) -> Mat4 {
+        Mat4::from_rotation_x(-self.pitch.to_radians())
+            * Mat4::from_rotation_z(self.yaw.to_radians())
+    }
+}
 
 impl From<Camera> for Mat4 {
     fn from(value: Camera) -> Self {
-        let mut ret = Mat4::IDENTITY;
-        // 90 degree turn.
-        const TURN: f32 = std::f32::consts::FRAC_PI_2;
-        // Oblique unit.
-        const OB: f32 = 0.5;
-
-        match value {
-            // Apply oblique shear.
-            ObliqueNorth | ObliqueEast | ObliqueSouth | ObliqueWest => {
-                ret.z_axis.x = -OB;
-                ret.z_axis.y = OB;
+        match value.projection {
+            Projection::Orthographic => {
+                // Apply oblique shear.
+                let mut shear = Mat4::IDENTITY;
+                shear.z_axis.x = -value.shear;
+                shear.z_axis.y = value.shear;
+
+                Mat4::from_scale(Vec3::splat(value.scale)) * shear * value.rotation()
             }
-        }
+            Projection::Perspective { eye, fov } => {
+                // Focal length in pixels.
+                let f = value.scale * PERSPECTIVE_HEIGHT / 2.0 / (fov.to_radians() / 2.0).tan();
 
-        match value {
-            // Rotate for facing.
-            ObliqueNorth => {}
-            ObliqueEast => ret *= Mat4::from_rotation_z(TURN),
-            ObliqueSouth => ret *= Mat4::from_rotation_z(2.0 * TURN),
-            ObliqueWest => ret *= Mat4::from_rotation_z(3.0 * TURN),
-        }
+                // Divide x and y by distance, and make the view space z the
+                // inverse distance so that it decreases away from the viewer
+                // like in orthographic views.
+                let perspective = Mat4::from_cols(
+                    vec4(f, 0.0, 0.0, 0.0),
+                    vec4(0.0, f, 0.0, 0.0),
+                    vec4(0.0, 0.0, 0.0, -1.0),
+                    vec4(0.0, 0.0, 1.0, 0.0),
+                );
 
-        ret
+                perspective * value.rotation() * Mat4::from_translation(-eye)
+            }
+        }
     }
 }
 
@@ -167,49 +479,216 @@ impl BoundingBox {
     }
 
     /// Return origin and size of the screen space bounding box.
-    pub fn screen_bounds(&self, camera: &Mat4) -> (IVec2, IVec2) {
-        let mut screen_min = Vec3::splat(f32::INFINITY);
-        let mut screen_max = Vec3::splat(f32::NEG_INFINITY);
+    ///
+    /// Perspective views are clipped to the field of view, which covers a
+    /// square of `PERSPECTIVE_HEIGHT` pixels at scale 1. If the box reaches
+    /// behind the eye, it can show anywhere in the field of view.
+    pub fn screen_bounds(&self, camera: &Camera) -> (IVec2, IVec2) {
+        let fov = camera
+            .is_perspective()
+            .then(|| camera.scale * PERSPECTIVE_HEIGHT / 2.0);
+        self.matrix_bounds(&Mat4::from(*camera), fov)
+    }
+
+    /// Screen space bounds through a camera matrix, clipped to `half_fov`
+    /// pixels from the center for perspective matrices.
+    fn matrix_bounds(&self, matrix: &Mat4, half_fov: Option<f32>) -> (IVec2, IVec2) {
+        let mut screen_min = Vec2::INFINITY;
+        let mut screen_max = Vec2::NEG_INFINITY;
         for p in self.corners() {
-            let p = camera.transform_point3(p);
+            let p = *matrix * p.extend(1.0);
+            if half_fov.is_some() && p.w <= NEAR_PLANE {
+                screen_min = Vec2::NEG_INFINITY;
+                screen_max = Vec2::INFINITY;
+                break;
+            }
+            let p = p.truncate().truncate() / p.w;
             screen_min = screen_min.min(p);
             screen_max = screen_max.max(p);
         }
-        let origin = screen_min.truncate().floor().as_ivec2();
-        let size = (screen_max - screen_min).truncate().ceil().as_ivec2();
+
+        if let Some(half) = half_fov {
+            let half = Vec2::splat(half);
+            screen_min = screen_min.clamp(-half, half);
+            screen_max = screen_max.clamp(-half, half);
+        }
+
+        let origin = screen_min.floor().as_ivec2();
+        let size = screen_max.ceil().as_ivec2() - origin;
 
         (origin, size)
     }
+
+    /// Return the pixels the box covers in the image space of `project`.
+    pub fn view_rect(&self, camera: &Camera) -> Rect {
+        screen_rect(self.screen_bounds(camera))
+    }
+
+    /// Clip a ray against the box.
+    ///
+    /// Return the ray parameters where the ray enters and exits the box, or
+    /// `None` if the ray misses the box. The entry parameter can be negative
+    /// if the origin is inside the box or past it.
+    pub fn intersect_ray(&self, origin: Vec3, dir: Vec3) -> Option<(f32, f32)> {
+        // Slab method.
+        let mut t_min = f32::NEG_INFINITY;
+        let mut t_max = f32::INFINITY;
+
+        for i in 0..3 {
+            if dir[i] == 0.0 {
+                // Parallel to slab, either always inside it or never.
+                if origin[i] < self.min[i] || origin[i] > self.max[i] {
+                    return None;
+                }
+                continue;
+            }
+
+            let t1 = (self.min[i] - origin[i]) / dir[i];
+            let t2 = (self.max[i] - origin[i]) / dir[i];
+            t_min = t_min.max(t1.min(t2));
+            t_max = t_max.min(t1.max(t2));
+        }
+
+        (t_min < t_max).then_some((t_min, t_max))
+    }
 }
 
-pub fn build_view<T>(model: &dyn Body<Value = T>, camera: &Mat4) -> HashMap<IVec2, (Vec3, T)> {
-    // How far to raytrace until you bail out.
-    const TRACE_LIMIT: usize = 256;
+/// Project a point into the image space of views built with `camera`.
+///
+/// Image space is the view space xy plane with the y axis flipped to point
+/// down. View pixel `p` covers the image space square from `p` to `p + 1`.
+pub fn project(camera: &Camera, pos: Vec3) -> Vec2 {
+    project_with(&Mat4::from(*camera), pos)
+}
 
-    let aabb = model.bounding_box();
+/// Same as `project` with the camera matrix, for projecting many points.
+pub(crate) fn project_with(matrix: &Mat4, pos: Vec3) -> Vec2 {
+    let pos = matrix.project_point3(pos);
+    vec2(pos.x, -pos.y)
+}
 
-    let (origin, size) = aabb.screen_bounds(camera);
+/// Ray through the center of view pixel `pixel`.
+///
+/// `inverse` is the inverse of the camera matrix. Return the ray origin and
+/// the normalized direction towards the scene.
+pub(crate) fn pixel_ray(inverse: &Mat4, pixel: IVec2) -> (Vec3, Vec3) {
+    // Ray pointing towards scene at negative z, shot through the center of
+    // the pixel. Flip y-axis when moving from image space to 3D space.
+    let (x, y) = (pixel.x as f32 + 0.5, -(pixel.y as f32 + 0.5));
 
-    let mut ret = HashMap::default();
+    // Unproject two points along the ray, in a perspective view these are
+    // one and two units away from the eye.
+    let pos = inverse.project_point3(vec3(x, y, 1.0));
+    let dir = (inverse.project_point3(vec3(x, y, 0.5)) - pos).normalize();
 
-    for y in 0..size.y {
-        for x in 0..size.x {
-            let view_pos = ivec2(x, y);
+    (pos, dir)
+}
 
-            // Flip y-axis when moving from image space to 3D space.
-            let (x, y) = (x as f32, size.y as f32 - y as f32 - 1.0);
-            // Ray pointing towards scene at negative z.
-            let pos = vec3(x + origin.x as f32, y + origin.y as f32, 0.0);
-            let dir = vec3(0.0, 0.0, -1.0);
+/// Trace the ray through view pixel `pixel` and return the first occupied
+/// cell it hits.
+///
+/// The ray parameter of the returned step is the distance from the ray
+/// origin of `pixel_ray`, and the normal is the face of the cell the ray
+/// entered through.
+pub(crate) fn trace_pixel<T>(
+    model: &(impl Body<Value = T> + ?Sized),
+    aabb: &BoundingBox,
+    inverse: &Mat4,
+    perspective: bool,
+    pixel: IVec2,
+) -> Option<(TraceStep, T)> {
+    let (pos, dir) = pixel_ray(inverse, pixel);
 
-            let pos = camera.inverse().transform_point3(pos);
-            let dir = camera.inverse().transform_vector3(dir);
+    // Only trace the part of the ray that's inside the model.
+    let (mut t_in, t_out) = aabb.intersect_ray(pos, dir)?;
+    if perspective {
+        // Nothing behind the eye is visible.
+        t_in = t_in.max(0.0);
+        if t_in >= t_out {
+            return None;
+        }
+    }
 
-            if let Some(result) = trace(pos, dir)
-                .take(TRACE_LIMIT)
-                .find_map(|cell| model.sample(cell).map(|val| (cell, val)))
-            {
-                ret.insert(view_pos, result);
+    let entry = pos + t_in * dir;
+    model.cast(entry, dir, t_out - t_in).map(|(mut step, val)| {
+        step.t += t_in;
+        if step.normal == IVec3::ZERO {
+            // Hit the first cell, the ray entered it through the
+            // bounding box.
+            step.normal = entry_normal(aabb, entry, dir);
+        }
+        (step, val)
+    })
+}
+
+/// Outward normal of the bounding box face a ray going in direction `dir`
+/// enters through at `pos`.
+fn entry_normal(aabb: &BoundingBox, pos: Vec3, dir: Vec3) -> IVec3 {
+    let (_, axis, sign) = (0..3)
+        .map(|i| {
+            if dir[i] > 0.0 {
+                ((pos[i] - aabb.min[i]).abs(), i, -1)
+            } else if dir[i] < 0.0 {
+                ((pos[i] - aabb.max[i]).abs(), i, 1)
+            } else {
+                (f32::INFINITY, i, 0)
+            }
+        })
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .unwrap();
+
+    let mut ret = IVec3::ZERO;
+    ret[axis] = sign;
+    ret
+}
+
+/// Map screen space bounds to the pixels they cover in the image space of
+/// `project`.
+fn screen_rect((origin, size): (IVec2, IVec2)) -> Rect {
+    Rect::new(
+        ivec2(origin.x, -(origin.y + size.y)),
+        ivec2(origin.x + size.x, -origin.y),
+    )
+}
+
+/// Render the model by tracing a ray through every view pixel.
+///
+/// The result maps the view pixels that hit the model to the hit cell and
+/// its value. Pixel positions are in the image space of `project`.
+pub fn build_view<T>(model: &dyn Body<Value = T>, camera: &Camera) -> HashMap<IVec2, (Vec3, T)> {
+    let rect = model.bounding_box().view_rect(camera);
+    let matrix = Mat4::from(*camera);
+    trace_view(model, rect, &matrix, camera.is_perspective())
+}
+
+/// Render the model through an orthographic camera matrix, such as one
+/// from `Mat4::from(camera)`.
+///
+/// Same as `build_view`, for code that works with camera matrices.
+pub fn build_view_matrix<T>(
+    model: &dyn Body<Value = T>,
+    matrix: &Mat4,
+) -> HashMap<IVec2, (Vec3, T)> {
+    let rect = screen_rect(model.bounding_box().matrix_bounds(matrix, None));
+    trace_view(model, rect, matrix, false)
+}
+
+fn trace_view<T>(
+    model: &dyn Body<Value = T>,
+    rect: Rect,
+    matrix: &Mat4,
+    perspective: bool,
+) -> HashMap<IVec2, (Vec3, T)> {
+    let aabb = model.bounding_box();
+    let inverse = matrix.inverse();
+
+    let mut ret = HashMap::default();
+
+    for y in rect.min.y..rect.max.y {
+        for x in rect.min.x..rect.max.x {
+            let pixel = ivec2(x, y);
+            if let Some((step, val)) = trace_pixel(model, &aabb, &inverse, perspective, pixel) {
+                ret.insert(pixel, (step.cell.as_vec3(), val));
             }
         }
     }
@@ -217,6 +696,35 @@ pub fn build_view<T>(model: &dyn Body<Value = T>, camera: &Mat4) -> HashMap<IVec
     ret
 }
 
+/// Multithreaded version of `build_view`.
+///
+/// Image rows are split between the threads of the current rayon thread
+/// pool. The result is identical to the one from `build_view`.
+#[cfg(feature = "rayon")]
+pub fn build_view_par<T: Send>(
+    model: &(dyn Body<Value = T> + Sync),
+    camera: &Camera,
+) -> HashMap<IVec2, (Vec3, T)> {
+    use rayon::prelude::*;
+
+    let aabb = model.bounding_box();
+
+    let rect = aabb.view_rect(camera);
+    let inverse = Mat4::from(*camera).inverse();
+    let perspective = camera.is_perspective();
+
+    (rect.min.y..rect.max.y)
+        .into_par_iter()
+        .flat_map_iter(|y| {
+            (rect.min.x..rect.max.x).filter_map(move |x| {
+                let pixel = ivec2(x, y);
+                trace_pixel(model, &aabb, &inverse, perspective, pixel)
+                    .map(|(step, val)| (pixel, (step.cell.as_vec3(), val)))
+            })
+        })
+        .collect()
+}
+
 /// Remove black outline from the image.
 pub fn clear_outline(image: &mut Image) {
     let color_key = *image.get_pixel(0, 0);
@@ -248,6 +756,7 @@ pub fn clear_outline(image: &mut Image) {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Rect {
     pub min: IVec2,
     pub max: IVec2,
@@ -276,6 +785,10 @@ impl Rect {
         Self::new(min, max + ivec2(1, 1))
     }
 
+    pub fn contains(&self, pos: IVec2) -> bool {
+        self.min.cmple(pos).all() && pos.cmplt(self.max).all()
+    }
+
     /// Map point within the rectangle to [0, 1[ range.
     pub fn normalize(&self, pos: IVec2) -> Vec2 {
         let size = self.max - self.min;
@@ -292,41 +805,57 @@ impl Rect {
 }
 
 pub trait DotVoxExt {
+    /// Set a voxel of model `model_idx` to the existing palette color
+    /// nearest to `color`.
+    ///
+    /// Looks up the palette on every call, use a `Palette` and
+    /// `set_voxel_index` to set many voxels or to add new colors.
+    ///
+    /// # Panics
+    ///
+    /// If a coordinate of `pos` is outside the 0 to 255 range.
     fn set_voxel(&mut self, model_idx: usize, pos: IVec3, color: Pixel);
+
+    /// Set a voxel of model `model_idx` to palette index `index`.
+    ///
+    /// VOX models can't reach past cell 255, use `ChunkedVoxels::to_vox` for
+    /// larger volumes.
+    ///
+    /// # Panics
+    ///
+    /// If a coordinate of `pos` is outside the 0 to 255 range or `index` is
+    /// 255, which VOX files can't store.
+    fn set_voxel_index(&mut self, model_idx: usize, pos: IVec3, index: u8);
 }
 
 impl DotVoxExt for DotVoxData {
     fn set_voxel(&mut self, model_idx: usize, pos: IVec3, color: Pixel) {
-        // Find the palette color that is closest to the color we want.
-        let palette_idx = self
-            .palette
-            .iter()
-            .enumerate()
-            .min_by_key(|(_, &p)| {
-                (p.r as i32 - color[0] as i32).abs()
-                    + (p.g as i32 - color[1] as i32).abs()
-                    + (p.b as i32 - color[2] as i32).abs()
-            })
-            .map(|(idx, _)| idx)
-            .unwrap();
+        let index = Palette::from_vox(self, Policy::Nearest).nearest_index(color);
+        self.set_voxel_index(model_idx, pos, index);
+    }
 
-        let (x, y, z) = (pos.x as u8, pos.y as u8, pos.z as u8);
+    fn set_voxel_index(&mut self, model_idx: usize, pos: IVec3, index: u8) {
+        let coord = |c: i32| {
+            u8::try_from(c).unwrap_or_else(|_| panic!("Voxel {pos} outside VOX model range"))
+        };
+        let (x, y, z) = (coord(pos.x), coord(pos.y), coord(pos.z));
+        assert!(
+            (index as usize) < PALETTE_SLOTS,
+            "Palette index {index} outside VOX palette range"
+        );
 
         let voxel_idx = self.models[model_idx]
             .voxels
             .iter()
             .position(|v| v.x == x && v.y == y && v.z == z)
             .unwrap_or_else(|| {
-                self.models[model_idx].voxels.push(dot_vox::Voxel {
-                    x,
-                    y,
-                    z,
-                    i: palette_idx as u8,
-                });
+                self.models[model_idx]
+                    .voxels
+                    .push(dot_vox::Voxel { x, y, z, i: index });
                 self.models[model_idx].voxels.len() - 1
             });
 
-        self.models[model_idx].voxels[voxel_idx].i = palette_idx as u8;
+        self.models[model_idx].voxels[voxel_idx].i = index;
     }
 }
 
@@ -343,4 +872,256 @@ mod tests {
         assert_eq!(rect.denormalize(vec2(0.0, 0.0)), ivec2(10, 20));
         assert_eq!(rect.denormalize(vec2(1.0, 1.0)), ivec2(30, 40));
     }
+
+    #[test]
+    fn camera_presets() {
+        // Classic oblique projection.
+        let camera = Mat4::from(Camera::OBLIQUE_NORTH);
+        assert_eq!(
+            camera.transform_point3(vec3(0.0, 0.0, 2.0)),
+            vec3(-1.0, 1.0, 2.0)
+        );
+
+        let camera = Mat4::from(Camera::OBLIQUE_EAST);
+        let p = camera.transform_point3(vec3(1.0, 0.0, 0.0));
+        assert!(p.distance(vec3(0.0, 1.0, 0.0)) < 1e-6);
+
+        // Isometric ground plane diamond is twice as wide as it's tall.
+        let camera = Mat4::from(Camera::ISOMETRIC);
+        let horizontal = camera.transform_vector3(vec3(-1.0, 1.0, 0.0));
+        let vertical = camera.transform_vector3(vec3(1.0, 1.0, 0.0));
+        assert!(horizontal.y.abs() < 1e-6 && vertical.x.abs() < 1e-6);
+        assert!((horizontal.x.abs() - 2.0 * vertical.y.abs()).abs() < 1e-6);
+
+        // Side view puts up on the screen up and looks at the front.
+        let camera = Mat4::from(Camera::SIDE);
+        assert!(camera.transform_vector3(Vec3::Z).distance(Vec3::Y) < 1e-6);
+        assert!(camera.transform_vector3(-Vec3::Y).distance(Vec3::Z) < 1e-6);
+    }
+
+    #[test]
+    fn view_pixels_match_projection() {
+        let mut grid = VoxelGrid::new(IVec3::ZERO, IVec3::splat(8));
+        grid.set(ivec3(3, 4, 5), 1);
+
+        for camera in [Camera::OBLIQUE_NORTH, Camera::ISOMETRIC, Camera::SIDE] {
+            let camera = camera.scaled(4.0);
+            let view = build_view(&grid, &camera);
+            let center = project(&camera, vec3(3.5, 4.5, 5.5)).floor().as_ivec2();
+            assert!(view.contains_key(&center));
+        }
+    }
+
+    #[test]
+    fn perspective_shrinks_with_distance() {
+        let mut grid = VoxelGrid::new(IVec3::ZERO, ivec3(4, 4, 1));
+        for y in 0..4 {
+            for x in 0..4 {
+                grid.set(ivec3(x, y, 0), 1);
+            }
+        }
+
+        let size_at = |distance: f32| {
+            let camera = Camera {
+                projection: Projection::Perspective {
+                    eye: vec3(2.0, 2.0, distance),
+                    fov: 30.0,
+                },
+                ..Camera::TOP_DOWN
+            };
+            build_view(&grid, &camera).len()
+        };
+
+        let (near, far) = (size_at(20.0), size_at(40.0));
+        assert!(far > 0);
+        // Twice as far should be a quarter of the area.
+        assert!((near as f32 / far as f32 - 4.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn matrix_view() {
+        let mut grid = VoxelGrid::new(IVec3::ZERO, IVec3::splat(4));
+        for z in 0..2 {
+            for x in 0..4 {
+                grid.set(ivec3(x, 1, z), x as u8);
+            }
+        }
+
+        for camera in [Camera::OBLIQUE_NORTH, Camera::ISOMETRIC.scaled(2.0)] {
+            assert_eq!(
+                build_view_matrix(&grid, &Mat4::from(camera)),
+                build_view(&grid, &camera)
+            );
+        }
+    }
+
+    #[test]
+    fn perspective_eye_inside_box() {
+        let mut grid = VoxelGrid::new(IVec3::ZERO, IVec3::splat(8));
+        for y in 0..8 {
+            for x in 0..8 {
+                grid.set(ivec3(x, y, 0), 1);
+            }
+        }
+
+        // Looking down at the floor from inside the bounding box, the box
+        // corners above the eye are behind it.
+        let camera = Camera {
+            projection: Projection::Perspective {
+                eye: vec3(4.0, 4.0, 4.0),
+                fov: 90.0,
+            },
+            ..Camera::TOP_DOWN
+        };
+        let rect = grid.bounding_box().view_rect(&camera);
+        assert_eq!(rect, Rect::new(IVec2::splat(-128), IVec2::splat(128)));
+
+        // The floor fills the whole field of view.
+        let view = build_view(&grid, &camera);
+        assert!(view.len() > 64 * 64);
+        assert!(view.keys().all(|p| rect.contains(*p)));
+    }
+
+    #[test]
+    fn ray_clipping() {
+        let aabb = BoundingBox::new(vec3(0.0, 0.0, 0.0), vec3(4.0, 4.0, 4.0));
+        assert_eq!(
+            aabb.intersect_ray(vec3(2.0, 2.0, 10.0), vec3(0.0, 0.0, -1.0)),
+            Some((6.0, 10.0))
+        );
+        assert_eq!(
+            aabb.intersect_ray(vec3(2.0, 2.0, 2.0), vec3(1.0, 0.0, 0.0)),
+            Some((-2.0, 2.0))
+        );
+        assert_eq!(
+            aabb.intersect_ray(vec3(5.0, 2.0, 10.0), vec3(0.0, 0.0, -1.0)),
+            None
+        );
+    }
+
+    #[test]
+    fn view_is_not_depth_limited() {
+        // Single voxel far below the camera plane.
+        let mut grid = VoxelGrid::new(ivec3(0, 0, -1000), ivec3(1, 1, 1001));
+        grid.set(ivec3(0, 0, -1000), 1);
+
+        let view = build_view(&grid, &Camera::TOP_DOWN);
+        assert_eq!(view.len(), 1);
+        assert_eq!(view[&ivec2(0, -1)], (vec3(0.0, 0.0, -1000.0), 1));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_view_matches_serial() {
+        let mut grid = VoxelGrid::new(IVec3::ZERO, IVec3::splat(16));
+        for z in 0..16 {
+            for y in 0..16 {
+                for x in 0..16 {
+                    if (x * 7 + y * 3 + z * 5) % 11 == 0 {
+                        grid.set(ivec3(x, y, z), (x + y + z) as u8);
+                    }
+                }
+            }
+        }
+
+        let camera = Camera::OBLIQUE_NORTH.rotated(17.0);
+        assert_eq!(build_view(&grid, &camera), build_view_par(&grid, &camera));
+
+        let (serial, parallel) = (
+            render_buffers(&grid, &camera),
+            render_buffers_par(&grid, &camera),
+        );
+        assert_eq!(serial.view(), parallel.view());
+        assert_eq!(serial.depth_image(), parallel.depth_image());
+        assert_eq!(serial.normal_image(), parallel.normal_image());
+    }
+
+    #[test]
+    fn trace_is_face_connected() {
+        let origin = vec3(0.3, 0.6, 0.2);
+        let dir = vec3(3.0, -2.0, 1.5);
+        let steps: Vec<_> = trace(origin, dir).take(50).collect();
+
+        assert_eq!(steps[0].cell, IVec3::ZERO);
+        assert_eq!(steps[0].normal, IVec3::ZERO);
+
+        for (a, b) in steps.iter().zip(steps.iter().skip(1)) {
+            // Every step moves across exactly one face, and the reported
+            // normal points back at the previous cell.
+            assert_eq!((b.cell - a.cell).abs().element_sum(), 1);
+            assert_eq!(b.cell + b.normal, a.cell);
+            assert!(b.t >= a.t);
+
+            // The entry point lies on the face the ray came through.
+            let entry = origin + b.t * dir;
+            let axis = (0..3).find(|&i| b.normal[i] != 0).unwrap();
+            let face = if b.normal[axis] < 0 {
+                b.cell[axis]
+            } else {
+                b.cell[axis] + 1
+            };
+            assert!((entry[axis] - face as f32).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn set_voxel_out_of_range() {
+        let mut data = DotVoxData {
+            version: 150,
+            models: vec![dot_vox::Model {
+                size: dot_vox::Size { x: 1, y: 1, z: 1 },
+                voxels: Vec::new(),
+            }],
+            palette: Vec::new(),
+            materials: Vec::new(),
+            scenes: Vec::new(),
+            layers: Vec::new(),
+        };
+        data.set_voxel_index(0, ivec3(0, 0, 0), 1);
+        assert_eq!(data.models[0].voxels.len(), 1);
+        // Would wrap around to cell 0 without the range check.
+        data.set_voxel_index(0, ivec3(256, 0, 0), 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn set_voxel_index_out_of_palette() {
+        let mut data = DotVoxData {
+            version: 150,
+            models: vec![dot_vox::Model {
+                size: dot_vox::Size { x: 1, y: 1, z: 1 },
+                voxels: Vec::new(),
+            }],
+            palette: Vec::new(),
+            materials: Vec::new(),
+            scenes: Vec::new(),
+            layers: Vec::new(),
+        };
+        // Written as index + 1, which doesn't fit in a byte.
+        data.set_voxel_index(0, ivec3(0, 0, 0), 255);
+    }
+
+    #[test]
+    fn set_voxel_color() {
+        let color = |r, g, b| dot_vox::Color { r, g, b, a: 255 };
+        let mut data = DotVoxData {
+            version: 150,
+            models: vec![dot_vox::Model {
+                size: dot_vox::Size { x: 2, y: 1, z: 1 },
+                voxels: Vec::new(),
+            }],
+            palette: vec![color(255, 255, 255), color(255, 0, 0), color(0, 0, 255)],
+            materials: Vec::new(),
+            scenes: Vec::new(),
+            layers: Vec::new(),
+        };
+
+        // Colors snap to the nearest palette color.
+        data.set_voxel(0, ivec3(0, 0, 0), Rgba([250, 10, 10, 255]));
+        data.set_voxel(0, ivec3(1, 0, 0), Rgba([0, 0, 200, 255]));
+        let indices: Vec<u8> = data.models[0].voxels.iter().map(|v| v.i).collect();
+        assert_eq!(indices, vec![1, 2]);
+        assert_eq!(data.palette.len(), 3);
+    }
 }
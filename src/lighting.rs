@@ -0,0 +1,326 @@
+use std::collections::HashSet;
+
+use glam::{ivec2, ivec3, vec3, IVec2, Mat4, Vec3};
+use image::Rgba;
+
+use crate::{pixel_ray, Body, BoundingBox, Camera, NormalEstimator, Pixel};
+
+/// Directional light.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Light {
+    /// Direction towards the light.
+    pub dir: Vec3,
+    pub intensity: f32,
+}
+
+impl Light {
+    pub fn new(dir: Vec3, intensity: f32) -> Self {
+        Light {
+            dir: dir.normalize_or_zero(),
+            intensity,
+        }
+    }
+}
+
+/// Lighting setup for shading voxel surfaces.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lighting {
+    pub lights: Vec<Light>,
+    /// Light level of surfaces that no light reaches.
+    pub ambient: f32,
+    /// Add the ambient light to the direct light. Otherwise the ambient
+    /// level is only a floor that surfaces facing away from the lights
+    /// don't go below.
+    pub additive: bool,
+    /// How much ambient occlusion darkens the ambient light, 0 turns it off
+    /// and 1 makes fully enclosed voxels get no ambient light.
+    pub occlusion: f32,
+    /// Quantize light levels into this many bands.
+    pub bands: Option<u32>,
+    /// How surface normals are estimated.
+    pub normals: NormalEstimator,
+    /// Block lights from voxels that have other voxels between them and the
+    /// light.
+    pub shadows: bool,
+}
+
+impl Default for Lighting {
+    fn default() -> Self {
+        Lighting {
+            lights: vec![Light::new(vec3(5.0, -3.0, 2.0), 1.0)],
+            ambient: 0.4,
+            additive: false,
+            occlusion: 0.0,
+            bands: None,
+            normals: Default::default(),
+            shadows: false,
+        }
+    }
+}
+
+impl Lighting {
+    /// Light level at the voxel at `pos`.
+    ///
+    /// Level 1 shows the voxel at its own color, but multiple lights can
+    /// make it go higher.
+    pub fn level<B: Body + ?Sized>(&self, model: &B, pos: Vec3) -> f32 {
+        let normal = model.estimate_normal(pos, self.normals);
+
+        let direct: f32 = self
+            .lights
+            .iter()
+            .map(|light| {
+                let facing = normal.dot(light.dir);
+                if facing <= 0.0 || (self.shadows && in_shadow(model, pos, normal, light.dir)) {
+                    0.0
+                } else {
+                    light.intensity * facing
+                }
+            })
+            .sum();
+
+        let ambient = if self.occlusion > 0.0 {
+            let open = openness(model, pos, normal);
+            self.ambient * (1.0 - self.occlusion * (1.0 - open))
+        } else {
+            self.ambient
+        };
+
+        let level = if self.additive {
+            ambient + direct
+        } else {
+            ambient.max(direct)
+        };
+
+        match self.bands {
+            Some(n) if n > 0 => {
+                // Band edges are evenly spaced between no light and full
+                // light, levels above full light stay as they are.
+                let n = n as f32;
+                if level < 1.0 {
+                    (level * n).ceil().max(1.0) / n
+                } else {
+                    level
+                }
+            }
+            _ => level,
+        }
+    }
+
+    /// Shade the color of the voxel at `pos`.
+    pub fn shade<B: Body + ?Sized>(&self, model: &B, pos: Vec3, color: Pixel) -> Pixel {
+        let level = self.level(model, pos);
+        let c = |i: usize| (color[i] as f32 * level).round().clamp(0.0, 255.0) as u8;
+        Rgba([c(0), c(1), c(2), color[3]])
+    }
+}
+
+/// Fraction of the neighbor cells in front of the voxel surface at `pos`
+/// that are empty.
+///
+/// Voxels in creases and corners are partially enclosed by their neighbors
+/// and get less ambient light.
+pub fn openness<B: Body + ?Sized>(model: &B, pos: Vec3, normal: Vec3) -> f32 {
+    let mut total = 0;
+    let mut open = 0;
+
+    for z in -1..=1 {
+        for y in -1..=1 {
+            for x in -1..=1 {
+                let d = ivec3(x, y, z).as_vec3();
+                if d.dot(normal) <= 0.0 {
+                    continue;
+                }
+                total += 1;
+                if model.sample(pos + d).is_none() {
+                    open += 1;
+                }
+            }
+        }
+    }
+
+    if total == 0 {
+        1.0
+    } else {
+        open as f32 / total as f32
+    }
+}
+
+/// Return whether other voxels block the light coming from direction `dir`
+/// to the surface of the voxel at `pos`.
+///
+/// The shadow ray starts from the empty space in front of the surface,
+/// along `normal`, so the voxel doesn't shadow itself.
+pub fn in_shadow<B: Body + ?Sized>(model: &B, pos: Vec3, normal: Vec3, dir: Vec3) -> bool {
+    let origin = pos.floor() + Vec3::splat(0.5) + normal;
+
+    let Some((t_in, t_out)) = model.bounding_box().intersect_ray(origin, dir) else {
+        return false;
+    };
+    let t_in = t_in.max(0.0);
+    if t_in >= t_out {
+        return false;
+    }
+
+    model.cast(origin + t_in * dir, dir, t_out - t_in).is_some()
+}
+
+/// Find the view pixels covered by the shadow `model` casts on the ground
+/// plane at z = 0 when lit from direction `dir`.
+///
+/// Pixel positions are in the image space of `project`, same as in
+/// `build_view`. Parts of the shadow that the model itself covers in the
+/// view are included.
+pub fn drop_shadow<B: Body + ?Sized>(model: &B, camera: &Camera, dir: Vec3) -> HashSet<IVec2> {
+    let mut ret = HashSet::default();
+
+    // Light from below the horizon doesn't reach the ground.
+    if dir.z <= 0.0 {
+        return ret;
+    }
+
+    let aabb = model.bounding_box();
+
+    // Area of the ground the shadow can fall on.
+    let (min, max) = aabb
+        .corners()
+        .fold((Vec3::INFINITY, Vec3::NEG_INFINITY), |(min, max), p| {
+            let p = p - dir * (p.z / dir.z);
+            (min.min(p), max.max(p))
+        });
+    let rect = BoundingBox::new(min, max).view_rect(camera);
+
+    let inverse = Mat4::from(*camera).inverse();
+    let perspective = camera.is_perspective();
+
+    for y in rect.min.y..rect.max.y {
+        for x in rect.min.x..rect.max.x {
+            let pixel = ivec2(x, y);
+            let (pos, ray) = pixel_ray(&inverse, pixel);
+            if ray.z == 0.0 {
+                continue;
+            }
+
+            let t = -pos.z / ray.z;
+            if perspective && t < 0.0 {
+                continue;
+            }
+            let ground = (pos + t * ray).with_z(0.0);
+
+            let Some((t_in, t_out)) = aabb.intersect_ray(ground, dir) else {
+                continue;
+            };
+            let t_in = t_in.max(0.0);
+            if t_in < t_out && model.cast(ground + t_in * dir, dir, t_out - t_in).is_some() {
+                ret.insert(pixel);
+            }
+        }
+    }
+
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{build_view, Camera, VoxelGrid};
+    use glam::IVec3;
+
+    #[test]
+    fn ambient_floor() {
+        let mut grid = VoxelGrid::new(IVec3::ZERO, IVec3::splat(4));
+        for z in 0..4 {
+            for y in 0..4 {
+                for x in 0..4 {
+                    grid.set(ivec3(x, y, z), 1);
+                }
+            }
+        }
+        let sun = vec3(5.0, -3.0, 2.0).normalize();
+        let (side, top) = (vec3(3.5, 1.5, 1.5), vec3(1.5, 1.5, 3.5));
+
+        // By default ambient light only lifts the dark side.
+        let lighting = Lighting::default();
+        assert!((lighting.level(&grid, side) - sun.x).abs() < 1e-6);
+        assert_eq!(lighting.level(&grid, top), 0.4);
+
+        let additive = Lighting {
+            additive: true,
+            ..lighting
+        };
+        assert!((additive.level(&grid, top) - (0.4 + sun.z)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn occlusion_darkens_creases() {
+        // Thick floor with a wall rising from one edge.
+        let mut grid = VoxelGrid::new(IVec3::ZERO, ivec3(4, 4, 4));
+        for y in 0..4 {
+            for x in 0..4 {
+                grid.set(ivec3(x, y, 0), 1);
+                grid.set(ivec3(x, y, 1), 1);
+                grid.set(ivec3(0, y, x), 1);
+            }
+        }
+
+        let lighting = Lighting {
+            lights: Vec::new(),
+            ambient: 1.0,
+            additive: false,
+            occlusion: 1.0,
+            bands: None,
+            normals: Default::default(),
+            shadows: false,
+        };
+
+        let open = lighting.level(&grid, vec3(3.5, 1.5, 1.5));
+        let crease = lighting.level(&grid, vec3(1.5, 1.5, 1.5));
+        assert_eq!(open, 1.0);
+        assert!(crease < open);
+
+        let banded = Lighting {
+            bands: Some(4),
+            ..lighting
+        };
+        assert_eq!(banded.level(&grid, vec3(1.5, 1.5, 1.5)) * 4.0 % 1.0, 0.0);
+    }
+
+    #[test]
+    fn pillar_casts_shadows() {
+        // Pillar standing on a thick floor.
+        let mut grid = VoxelGrid::new(IVec3::ZERO, ivec3(8, 8, 8));
+        for y in 0..8 {
+            for x in 0..8 {
+                grid.set(ivec3(x, y, 0), 1);
+                grid.set(ivec3(x, y, 1), 1);
+            }
+        }
+        for z in 2..8 {
+            grid.set(ivec3(4, 4, z), 1);
+        }
+
+        // Light coming low from positive x.
+        let light = Light::new(vec3(1.0, 0.0, 1.0), 1.0);
+        let lighting = Lighting {
+            lights: vec![light],
+            ambient: 0.0,
+            shadows: true,
+            ..Default::default()
+        };
+        assert_eq!(lighting.level(&grid, vec3(2.0, 4.0, 1.0)), 0.0);
+        assert!(lighting.level(&grid, vec3(6.0, 4.0, 1.0)) > 0.0);
+        assert!(lighting.level(&grid, vec3(2.0, 2.0, 1.0)) > 0.0);
+
+        // Floating pillar drops a shadow on the ground next to it.
+        let mut grid = VoxelGrid::new(IVec3::ZERO, ivec3(8, 8, 8));
+        for z in 2..8 {
+            grid.set(ivec3(4, 4, z), 1);
+        }
+        let camera = Camera::TOP_DOWN;
+        let shadow = drop_shadow(&grid, &camera, light.dir);
+        let view = build_view(&grid, &camera);
+        assert!(!shadow.is_empty());
+        assert!(shadow.iter().any(|p| !view.contains_key(p)));
+        assert!(drop_shadow(&grid, &camera, vec3(1.0, 0.0, -1.0)).is_empty());
+    }
+}
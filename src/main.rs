@@ -1,13 +1,33 @@
-use std::{fs::File, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{anyhow, Result};
-use clap::{Args, Parser, Subcommand};
-use glam::{vec3, IVec2, Mat4, Vec3};
-use voxelize::{Body, Camera, DotVoxExt, Image, Rect};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use dot_vox::DotVoxData;
+use glam::{ivec2, ivec3, uvec2, vec3, IVec2, IVec3, Mat4, Quat, UVec2, Vec3};
+use image::{
+    codecs::gif::{GifEncoder, Repeat},
+    Delay, Frame, Rgba,
+};
+use serde_json::json;
+use voxelize::{
+    carve::{self, FocusImage, Prism},
+    drop_shadow, Anchor, Body, BoundingBox, Brickmap, Camera, Canvas, Connectivity, DotVoxExt,
+    Image, Light, Lighting, NormalEstimator, Outline, OutlineColor, Padding, Palette, Policy,
+    Projection, Rect, RenderBuffers, RenderOptions, Scene,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    /// Number of worker threads to render with, defaults to one per CPU.
+    #[cfg(feature = "rayon")]
+    #[arg(long, global = true)]
+    threads: Option<usize>,
+
     #[command(subcommand)]
     command: Command,
 }
@@ -19,147 +39,1509 @@ enum Command {
 
     /// Paint the surface of a voxel model using a reference image.
     Paint(PaintArgs),
+
+    /// Render a sprite sheet of the model turned to evenly spaced facings.
+    Sheet(SheetArgs),
+
+    /// Render the animation frames of a scene.
+    ///
+    /// Every keyframe lasts until the next one. VOX files don't say how
+    /// long the last keyframe lasts, it gets a single animation frame.
+    Animate(AnimateArgs),
+
+    /// Build a voxel model from sprites of it seen from different directions.
+    Carve(CarveArgs),
+}
+
+#[derive(Args, Debug)]
+struct CarveArgs {
+    /// Sprite and the camera preset it is drawn with, as
+    /// `path:preset[:x,y][:scale]`.
+    ///
+    /// `x,y` is the sprite pixel where the model origin is drawn and `scale`
+    /// is sprite pixels per voxel. Without `x,y` the top row and the left
+    /// column of the sprite must have a single marker pixel each, showing
+    /// where the model origin is.
+    #[arg(long = "view", value_parser = parse_view, required = true)]
+    views: Vec<View>,
+
+    /// Override preset oblique shear.
+    #[arg(long)]
+    shear: Option<f32>,
+
+    /// Also carve away surface voxels whose colors in the views that see
+    /// them differ by more than this RGB distance.
+    #[arg(long)]
+    consistency: Option<f32>,
+
+    /// Dither colors that don't fit in the palette.
+    #[arg(long)]
+    dither: bool,
+
+    /// The VOX file to write.
+    #[arg(short, long, default_value = "output.vox")]
+    output: PathBuf,
+}
+
+fn parse_ivec2(s: &str) -> Result<IVec2> {
+    let Some((x, y)) = s.split_once(',') else {
+        return Err(anyhow!("Expected x,y"));
+    };
+    Ok(ivec2(x.trim().parse()?, y.trim().parse()?))
+}
+
+fn parse_size(s: &str) -> Result<UVec2> {
+    let Some((w, h)) = s.split_once(',') else {
+        return Err(anyhow!("Expected width,height"));
+    };
+    let size = uvec2(w.trim().parse()?, h.trim().parse()?);
+    if size.min_element() == 0 {
+        return Err(anyhow!("Size must not be zero"));
+    }
+    Ok(size)
+}
+
+fn parse_fps(s: &str) -> Result<f32> {
+    let fps: f32 = s.trim().parse()?;
+    if !(fps.is_finite() && fps > 0.0) {
+        return Err(anyhow!("Frame rate must be positive"));
+    }
+    Ok(fps)
+}
+
+/// Sprite of the model seen through a camera preset.
+#[derive(Clone, Debug)]
+struct View {
+    path: PathBuf,
+    preset: Preset,
+    /// Sprite pixel of the model origin, overrides focus pixels.
+    offset: Option<IVec2>,
+    /// Sprite pixels per voxel.
+    scale: f32,
+}
+
+impl View {
+    fn camera(&self) -> Camera {
+        Camera::from(self.preset).scaled(self.scale)
+    }
+}
+
+fn parse_view(s: &str) -> Result<View> {
+    fn split(s: &str) -> Result<(&str, &str)> {
+        s.rsplit_once(':')
+            .ok_or_else(|| anyhow!("Expected path:preset[:x,y][:scale]"))
+    }
+
+    // The optional fields go after the preset, peel them off from the end
+    // so that paths can have colons in them.
+    let mut rest = s;
+    let (head, field) = split(rest)?;
+    let mut scale = 1.0;
+    if let Ok(x) = field.trim().parse::<f32>() {
+        if !(x.is_finite() && x > 0.0) {
+            return Err(anyhow!("View scale must be positive"));
+        }
+        scale = x;
+        rest = head;
+    }
+
+    let (head, field) = split(rest)?;
+    let mut offset = None;
+    if field.contains(',') {
+        offset = Some(parse_ivec2(field)?);
+        rest = head;
+    }
+
+    let (path, preset) = split(rest)?;
+    let preset = Preset::from_str(preset, true).map_err(|e| anyhow!(e))?;
+    Ok(View {
+        path: PathBuf::from(path),
+        preset,
+        offset,
+        scale,
+    })
+}
+
+#[derive(Args, Debug)]
+struct AnimateArgs {
+    /// The VOX scene to render.
+    model: String,
+
+    #[command(flatten)]
+    camera: CameraArgs,
+
+    /// Animation frames per second in the VOX file.
+    #[arg(long, default_value = "10", value_parser = parse_fps)]
+    fps: f32,
+
+    /// Write an animated GIF instead of a sprite strip.
+    #[arg(long)]
+    gif: bool,
+
+    #[command(flatten)]
+    shading: ShadingArgs,
+
+    #[command(flatten)]
+    outline: OutlineArgs,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args, Debug)]
+struct SheetArgs {
+    /// The VOX model to render.
+    model: String,
+
+    #[command(flatten)]
+    camera: CameraArgs,
+
+    /// Number of facings to render.
+    #[arg(
+        long,
+        default_value = "8",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+    )]
+    frames: usize,
+
+    /// Number of frames per sheet row, defaults to all frames on one row.
+    #[arg(long)]
+    columns: Option<usize>,
+
+    #[command(flatten)]
+    shading: ShadingArgs,
+
+    #[command(flatten)]
+    outline: OutlineArgs,
+
+    #[command(flatten)]
+    output: OutputArgs,
 }
 
 #[derive(Args, Debug)]
 struct PaintArgs {
-    /// Sample sprite is viewed from the front.
-    #[arg(long, default_value = "true")]
-    front: bool,
+    /// Name of the model to paint in a multi-model scene, defaults to the
+    /// first model.
+    #[arg(long = "model")]
+    target: Option<String>,
+
+    /// Sample sprite and the camera preset it is drawn with, as
+    /// `path:preset[:x,y][:scale]`.
+    ///
+    /// `x,y` is the sprite pixel where the model origin is drawn and `scale`
+    /// is sprite pixels per voxel. Without `x,y` the sprite is registered by
+    /// its focus pixels if it has them and stretched over the view if not.
+    /// Every voxel gets its color from the view that faces it most directly.
+    #[arg(long = "view", value_parser = parse_view, required = true)]
+    views: Vec<View>,
+
+    /// Blend the colors of all the views that see a voxel, weighted by how
+    /// directly they face it.
+    #[arg(long)]
+    blend: bool,
+
+    /// How to fit sprite colors into the model palette.
+    #[arg(long, value_enum, default_value = "exact")]
+    palette: PalettePolicy,
 
-    /// Sample sprite is viewed from the back.
-    #[arg(long, conflicts_with = "front")]
-    back: bool,
+    /// Palette indices whose colors must not be changed.
+    #[arg(long, value_delimiter = ',')]
+    lock: Vec<u8>,
 
-    /// Sample sprite to paint on model.
+    /// Dither colors that don't fit in the palette.
     #[arg(long)]
-    src: String,
+    dither: bool,
 
-    /// The VOX model to paint.
+    /// The VOX file to paint.
     model: String,
 }
 
+#[derive(Args, Debug)]
+struct ShadingArgs {
+    /// Apply procedural shading.
+    #[arg(long)]
+    shading: bool,
+
+    /// Directional light as x,y,z pointing towards the light, with an
+    /// optional :intensity. Replaces the default sun, can be given several
+    /// times.
+    #[arg(long = "light", value_parser = parse_light, allow_hyphen_values = true, requires = "shading")]
+    lights: Vec<Light>,
+
+    /// Light level of unlit surfaces.
+    #[arg(long, default_value = "0.4", requires = "shading")]
+    ambient: f32,
+
+    /// Add the ambient light to the light from the light sources instead of
+    /// using it as the lowest light level.
+    #[arg(long, requires = "shading")]
+    add_ambient: bool,
+
+    /// How much ambient occlusion darkens creases, from 0 to 1.
+    #[arg(long, default_value = "0.0", requires = "shading")]
+    occlusion: f32,
+
+    /// Quantize light levels into this many shade bands.
+    #[arg(long, requires = "shading")]
+    bands: Option<u32>,
+
+    /// Snap shaded colors to the nearest color in the model palette.
+    #[arg(long, requires = "shading")]
+    snap_palette: bool,
+
+    /// Cast shadows from the lights.
+    #[arg(long, requires = "shading")]
+    shadows: bool,
+
+    /// How to estimate surface normals for shading and smooth normal maps.
+    #[arg(long, value_enum, default_value = "neighbors")]
+    normals: NormalArg,
+
+    /// Radius in voxels for the gradient and plane fit normals.
+    #[arg(long, default_value = "2")]
+    normal_radius: u32,
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum NormalArg {
+    /// Directions to the empty neighbors of the voxel.
+    Neighbors,
+    /// Gradient of blurred voxel occupancy.
+    Gradient,
+    /// Plane fitted to the nearby surface voxels.
+    PlaneFit,
+}
+
+impl ShadingArgs {
+    fn lighting(&self) -> Lighting {
+        let mut lighting = Lighting {
+            ambient: self.ambient,
+            additive: self.add_ambient,
+            occlusion: self.occlusion,
+            bands: self.bands,
+            normals: self.normals(),
+            shadows: self.shadows,
+            ..Default::default()
+        };
+        if !self.lights.is_empty() {
+            lighting.lights = self.lights.clone();
+        }
+        lighting
+    }
+
+    fn normals(&self) -> NormalEstimator {
+        match self.normals {
+            NormalArg::Neighbors => NormalEstimator::Neighbors,
+            NormalArg::Gradient => NormalEstimator::Gradient(self.normal_radius),
+            NormalArg::PlaneFit => NormalEstimator::PlaneFit(self.normal_radius),
+        }
+    }
+
+    /// The strongest light.
+    fn key_light(&self) -> Light {
+        self.lighting()
+            .lights
+            .into_iter()
+            .max_by(|a, b| a.intensity.total_cmp(&b.intensity))
+            .expect("no lights")
+    }
+}
+
+fn parse_light(s: &str) -> Result<Light> {
+    let (dir, intensity) = match s.split_once(':') {
+        Some((dir, intensity)) => (dir, intensity.trim().parse()?),
+        None => (s, 1.0),
+    };
+    Ok(Light::new(parse_vec3(dir)?, intensity))
+}
+
+/// How to color the voxels in rendered images.
+struct Shading {
+    lighting: Option<Lighting>,
+    /// Palette to snap lit colors to.
+    palette: Option<Palette>,
+}
+
+impl Shading {
+    fn new(args: &ShadingArgs, scene: &DotVoxData) -> Self {
+        if !args.shading {
+            return Shading {
+                lighting: None,
+                palette: None,
+            };
+        }
+
+        Shading {
+            lighting: Some(args.lighting()),
+            palette: args
+                .snap_palette
+                .then(|| Palette::from_vox(scene, Policy::Nearest)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum PalettePolicy {
+    /// Add new colors to unused palette slots.
+    Exact,
+    /// Use the nearest existing palette color.
+    Nearest,
+}
+
+impl From<PalettePolicy> for Policy {
+    fn from(value: PalettePolicy) -> Self {
+        match value {
+            PalettePolicy::Exact => Policy::Exact,
+            PalettePolicy::Nearest => Policy::Nearest,
+        }
+    }
+}
+
 #[derive(Args, Debug)]
 struct DumpArgs {
     /// The VOX model to dump.
     model: String,
 
+    #[command(flatten)]
+    camera: CameraArgs,
+
+    #[command(flatten)]
+    shading: ShadingArgs,
+
+    #[command(flatten)]
+    outline: OutlineArgs,
+
+    #[command(flatten)]
+    output: OutputArgs,
+
+    /// Also write a screen space normal map of the sprite.
+    #[arg(
+        long,
+        value_name = "NORMALS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "faces",
+    )]
+    normal_map: Option<NormalMode>,
+
+    /// Also write a 16-bit depth map of the sprite, nearest surfaces are
+    /// white.
+    #[arg(long)]
+    depth_map: bool,
+
+    /// Also write the palette indices of the sprite pixels as a grayscale
+    /// image, stored plus one so that empty pixels are black.
+    #[arg(long)]
+    index_map: bool,
+
+    /// Also write the shadow the model drops on the ground under the
+    /// strongest light into a separate image, with this opacity.
+    #[arg(
+        long,
+        value_name = "OPACITY",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "0.5",
+    )]
+    drop_shadow: Option<f32>,
+}
+
+#[derive(Args, Debug)]
+struct OutputArgs {
+    /// Empty pixels around the sprite, either one value for all sides or
+    /// top,right,bottom,left.
+    #[arg(long, value_parser = parse_padding, default_value = "1")]
+    padding: Padding,
+
+    /// Fill the background with this RRGGBB color key instead of leaving it
+    /// transparent.
+    #[arg(long, value_parser = parse_color)]
+    background: Option<Rgba<u8>>,
+
+    /// Fixed canvas size as width,height, including the padding.
+    #[arg(long, value_parser = parse_size)]
+    canvas: Option<UVec2>,
+
+    /// Where to place the sprite on the canvas. On a fixed canvas the
+    /// bottom center of the model goes to this point, so sprites rendered
+    /// at different yaws line up.
+    #[arg(long, value_enum, default_value = "center")]
+    anchor: AnchorArg,
+
+    /// Round canvas width and height up to powers of two.
+    #[arg(long)]
+    power_of_two: bool,
+}
+
+impl OutputArgs {
+    fn options(&self) -> RenderOptions {
+        RenderOptions {
+            padding: self.padding,
+            background: self.background.unwrap_or(Rgba([0, 0, 0, 0])),
+            size: self.canvas,
+            anchor: self.anchor.into(),
+            power_of_two: self.power_of_two,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum AnchorArg {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl From<AnchorArg> for Anchor {
+    fn from(anchor: AnchorArg) -> Self {
+        match anchor {
+            AnchorArg::TopLeft => Anchor::TopLeft,
+            AnchorArg::Top => Anchor::Top,
+            AnchorArg::TopRight => Anchor::TopRight,
+            AnchorArg::Left => Anchor::Left,
+            AnchorArg::Center => Anchor::Center,
+            AnchorArg::Right => Anchor::Right,
+            AnchorArg::BottomLeft => Anchor::BottomLeft,
+            AnchorArg::Bottom => Anchor::Bottom,
+            AnchorArg::BottomRight => Anchor::BottomRight,
+        }
+    }
+}
+
+fn parse_padding(s: &str) -> Result<Padding> {
+    let values = s
+        .split(',')
+        .map(|c| c.trim().parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()?;
+    match values[..] {
+        [n] => Ok(Padding::uniform(n)),
+        [top, right, bottom, left] => Ok(Padding {
+            left,
+            top,
+            right,
+            bottom,
+        }),
+        _ => Err(anyhow!("Expected one value or top,right,bottom,left")),
+    }
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum NormalMode {
+    /// Normals of the voxel faces the view rays hit.
+    Faces,
+    /// Normals estimated from the surrounding voxels, as selected with
+    /// --normals.
+    Smooth,
+}
+
+#[derive(Args, Debug)]
+struct OutlineArgs {
+    /// Draw an outline around the sprite.
+    #[arg(long)]
+    outline: bool,
+
+    /// Outline color as RRGGBB hex.
+    #[arg(long, value_parser = parse_color, requires = "outline", conflicts_with = "selout")]
+    outline_color: Option<Rgba<u8>>,
+
+    /// Color outline pixels with the adjacent sprite color darkened to this
+    /// fraction of its brightness.
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "0.5",
+        requires = "outline",
+    )]
+    selout: Option<f32>,
+
+    /// Use 8-connected outlines that also cover diagonal neighbors.
+    #[arg(long, requires = "outline")]
+    diagonal_outline: bool,
+
+    /// Also outline internal edges where depth jumps by more than this many
+    /// voxels.
+    #[arg(
+        long,
+        value_name = "DEPTH",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "2",
+        requires = "outline",
+    )]
+    depth_edges: Option<f32>,
+}
+
+impl OutlineArgs {
+    fn outline(&self) -> Option<Outline> {
+        if !self.outline {
+            return None;
+        }
+
+        let color = match (self.selout, self.outline_color) {
+            (Some(level), _) => OutlineColor::Selout(level),
+            (None, Some(color)) => OutlineColor::Fixed(color),
+            (None, None) => Default::default(),
+        };
+        let connectivity = if self.diagonal_outline {
+            Connectivity::Eight
+        } else {
+            Connectivity::Four
+        };
+
+        Some(Outline {
+            color,
+            connectivity,
+            depth_edges: self.depth_edges,
+        })
+    }
+}
+
+fn parse_color(s: &str) -> Result<Rgba<u8>> {
+    let s = s.trim_start_matches('#');
+    if s.len() != 6 {
+        return Err(anyhow!("Expected RRGGBB"));
+    }
+    let c = u32::from_str_radix(s, 16)?;
+    Ok(Rgba([(c >> 16) as u8, (c >> 8) as u8, c as u8, 255]))
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum Preset {
+    North,
+    East,
+    South,
+    West,
+    Isometric,
+    TopDown,
+    Side,
+}
+
+impl From<Preset> for Camera {
+    fn from(value: Preset) -> Self {
+        match value {
+            Preset::North => Camera::OBLIQUE_NORTH,
+            Preset::East => Camera::OBLIQUE_EAST,
+            Preset::South => Camera::OBLIQUE_SOUTH,
+            Preset::West => Camera::OBLIQUE_WEST,
+            Preset::Isometric => Camera::ISOMETRIC,
+            Preset::TopDown => Camera::TOP_DOWN,
+            Preset::Side => Camera::SIDE,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct CameraArgs {
+    /// Camera preset to start from.
+    #[arg(long, value_enum, default_value = "north")]
+    camera: Preset,
+
     /// How big should the output image be.
     #[arg(long, default_value = "1.0")]
     scale: f32,
 
-    /// Rotation in degrees
+    /// Rotation in degrees, added to the preset's rotation.
     #[arg(long, default_value = "0.0")]
     yaw: f32,
 
-    /// Apply procedural shading.
+    /// Override preset tilt in degrees, 0 is top-down and 90 is side-on.
     #[arg(long)]
-    shading: bool,
+    pitch: Option<f32>,
+
+    /// Override preset oblique shear.
+    #[arg(long)]
+    shear: Option<f32>,
+
+    /// Use a perspective view with this vertical field of view in degrees.
+    #[arg(long, value_parser = parse_fov)]
+    fov: Option<f32>,
+
+    /// Eye position for perspective view as x,y,z, defaults to a position
+    /// where the whole model is visible.
+    #[arg(long, value_parser = parse_vec3, requires = "fov")]
+    eye: Option<Vec3>,
+}
+
+fn parse_fov(s: &str) -> Result<f32> {
+    let fov: f32 = s.trim().parse()?;
+    if !(fov > 0.0 && fov < 180.0) {
+        return Err(anyhow!("Field of view must be between 0 and 180 degrees"));
+    }
+    Ok(fov)
+}
+
+fn parse_vec3(s: &str) -> Result<Vec3> {
+    let coords = s
+        .split(',')
+        .map(|c| c.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()?;
+    let [x, y, z] = coords[..] else {
+        return Err(anyhow!("Expected three comma-separated coordinates"));
+    };
+    Ok(vec3(x, y, z))
+}
+
+impl CameraArgs {
+    /// Build the camera for viewing a model with the given bounds, turned
+    /// by extra `yaw` degrees.
+    ///
+    /// An explicit perspective eye position orbits around the center of the
+    /// model along with the yaw.
+    fn camera(&self, aabb: &BoundingBox, yaw: f32) -> Camera {
+        let ret = Camera::from(self).rotated(yaw);
+        match (self.fov, self.eye) {
+            (Some(fov), Some(eye)) => {
+                let center = (aabb.min + aabb.max) / 2.0;
+                let eye = center + Quat::from_rotation_z(-yaw.to_radians()) * (eye - center);
+                Camera {
+                    projection: Projection::Perspective { eye, fov },
+                    ..ret
+                }
+            }
+            (Some(fov), None) => ret.framing(aabb, fov),
+            _ => ret,
+        }
+    }
+}
+
+impl From<&CameraArgs> for Camera {
+    fn from(args: &CameraArgs) -> Self {
+        let mut ret = Camera::from(args.camera)
+            .rotated(args.yaw)
+            .scaled(args.scale);
+        if let Some(pitch) = args.pitch {
+            ret.pitch = pitch;
+        }
+        if let Some(shear) = args.shear {
+            ret.shear = shear;
+        }
+        ret
+    }
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+
+    #[cfg(feature = "rayon")]
+    if let Some(threads) = cli.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
+
     match cli.command {
-        Command::Dump(args) => dump(args.scale, args.shading, args.yaw, &args.model)?,
-        Command::Paint(args) => {
-            let camera = if args.back {
-                Camera::ObliqueSouth
-            } else {
-                // Default is front
-                Camera::ObliqueNorth
-            };
-            paint(&args.model, camera, &args.src)?
-        }
+        Command::Dump(args) => dump(&args)?,
+        Command::Paint(args) => paint(&args)?,
+        Command::Sheet(args) => sheet(&args)?,
+        Command::Carve(args) => carve(&args)?,
+        Command::Animate(args) => animate(&args)?,
     }
     Ok(())
 }
 
-fn dump(scale: f32, shading: bool, yaw: f32, model: &str) -> Result<()> {
-    let output_name = PathBuf::from(model).with_extension("png");
+/// Build a view using all the available threads.
+fn build_view<T: Send>(
+    model: &(dyn Body<Value = T> + Sync),
+    camera: &Camera,
+) -> HashMap<IVec2, (Vec3, T)> {
+    #[cfg(feature = "rayon")]
+    return voxelize::build_view_par(model, camera);
+
+    #[cfg(not(feature = "rayon"))]
+    voxelize::build_view(model, camera)
+}
+
+/// Render per-pixel layers using all the available threads.
+fn render_buffers<T: Send>(
+    model: &(dyn Body<Value = T> + Sync),
+    camera: &Camera,
+) -> RenderBuffers<T> {
+    #[cfg(feature = "rayon")]
+    return voxelize::render_buffers_par(model, camera);
+
+    #[cfg(not(feature = "rayon"))]
+    voxelize::render_buffers(model, camera)
+}
+
+/// Look up the color for a view pixel that hit voxel `idx` at `pos`.
+fn voxel_color(
+    scene: &DotVoxData,
+    model: &impl Body,
+    pos: Vec3,
+    idx: u8,
+    shading: &Shading,
+) -> Rgba<u8> {
+    let color = scene.palette[idx as usize];
+    let color = Rgba([color.r, color.g, color.b, 255]);
+
+    let Some(lighting) = &shading.lighting else {
+        return color;
+    };
+
+    let color = lighting.shade(model, pos, color);
+    match &shading.palette {
+        Some(palette) => palette.color(palette.nearest_index(color)),
+        None => color,
+    }
+}
+
+/// Draw the view pixels of a model and then their outline on `canvas`.
+///
+/// `color` gives the colors of the view pixels from the hit cell and its
+/// palette index. Selective outlines blend towards `background` where the
+/// canvas is empty.
+fn draw_view(
+    canvas: &mut Canvas,
+    view: &HashMap<IVec2, (Vec3, u8)>,
+    camera: &Camera,
+    outline: Option<&Outline>,
+    background: Rgba<u8>,
+    color: impl Fn(Vec3, u8) -> Rgba<u8>,
+) {
+    for (pos, (p, idx)) in view {
+        canvas.put(*pos, color(*p, *idx));
+    }
+
+    if let Some(outline) = outline {
+        let pixels = outline.pixels(view, camera, |pos| canvas.get(pos).unwrap_or(background));
+        for (pos, color) in pixels {
+            canvas.put(pos, color);
+        }
+    }
+}
+
+/// Bottom center of the model, where fixed size canvases are anchored so
+/// that the model stays in place between views.
+fn model_pivot(aabb: &BoundingBox, camera: &Camera) -> IVec2 {
+    let anchor = ((aabb.min + aabb.max) / 2.0).with_z(aabb.min.z);
+    voxelize::project(camera, anchor).floor().as_ivec2()
+}
+
+fn dump(args: &DumpArgs) -> Result<()> {
+    let output_name = PathBuf::from(&args.model).with_extension("png");
 
-    let scene = dot_vox::load(model).map_err(|e| anyhow!(e))?;
+    let scene = dot_vox::load(&args.model).map_err(|e| anyhow!(e))?;
+    let shading = Shading::new(&args.shading, &scene);
 
-    let camera = Mat4::from(Camera::ObliqueNorth);
-    // Pull camera backwards to see the model.
-    // Scale according to scale param.
-    let camera = Mat4::from_scale(Vec3::splat(scale))
-        * Mat4::from_translation(vec3(0.0, 0.0, -50.0))
-        * camera
-        * Mat4::from_rotation_z(yaw.to_radians());
+    let model = Brickmap::new(Scene::try_from(&scene)?);
+    let camera = args.camera.camera(&model.bounding_box(), 0.0);
 
-    let view = voxelize::build_view(&scene.models[0], &camera);
+    // Trace the model once, the normal map comes from the same layers as
+    // the image.
+    let buffers = render_buffers(&model, &camera);
+    let view = buffers.view();
 
-    let (p1, p2) = view
-        .keys()
-        .fold((IVec2::MAX, IVec2::MIN), |(min, max), &pos| {
-            (min.min(pos), max.max(pos))
-        });
+    let shadow = match args.drop_shadow {
+        Some(_) => drop_shadow(&model, &camera, args.shading.key_light().dir),
+        None => Default::default(),
+    };
 
-    // Size of the border to put around the image in pixels.
-    const BORDER: u32 = 1;
+    // The shadow layer is aligned with the model image, so make room for
+    // both.
+    let mut rect = Rect::from_points(view.keys().chain(&shadow).copied());
+    let outline = args.outline.outline();
+    if let Some(outline) = &outline {
+        rect = outline.grow(rect);
+    }
+    let options = args.output.options();
+
+    let pivot = model_pivot(&model.bounding_box(), &camera);
+    let mut canvas = Canvas::with_pivot(rect, Some(pivot), &options);
+    draw_view(
+        &mut canvas,
+        &view,
+        &camera,
+        outline.as_ref(),
+        options.background,
+        |p, idx| voxel_color(&scene, &model, p, idx, &shading),
+    );
+    report_clipped(canvas.clipped());
+
+    canvas.image().save(output_name)?;
+
+    // Extra layers are named after the model.
+    let stem = Path::new(&args.model).with_extension("");
+
+    if let Some(opacity) = args.drop_shadow {
+        let alpha = (opacity.clamp(0.0, 1.0) * 255.0).round() as u8;
+        let mut layer = canvas.layer(Rgba([0, 0, 0, 0]));
+        for pos in &shadow {
+            layer.put(*pos, Rgba([0, 0, 0, alpha]));
+        }
+        report_clipped(layer.clipped());
+
+        layer
+            .image()
+            .save(format!("{}_shadow.png", stem.display()))?;
+    }
+
+    // Buffer layers cover only the traced view, lay them out like the
+    // sprite. Their empty pixels are zero, transparent for the normal map.
+    let min = buffers.rect().min;
+
+    if let Some(mode) = args.normal_map {
+        let normals = match mode {
+            NormalMode::Faces => buffers.normal_image(),
+            NormalMode::Smooth => buffers.smooth_normal_image(&model, args.shading.normals()),
+        };
+        canvas
+            .align(min, &normals)
+            .save(format!("{}_normal.png", stem.display()))?;
+    }
+
+    if args.depth_map {
+        canvas
+            .align(min, &buffers.depth_image())
+            .save(format!("{}_depth.png", stem.display()))?;
+    }
+
+    if args.index_map {
+        canvas
+            .align(min, &buffers.index_image())
+            .save(format!("{}_index.png", stem.display()))?;
+    }
+
+    Ok(())
+}
+
+fn sheet(args: &SheetArgs) -> Result<()> {
+    // Keep clear of the image dump writes for the model.
+    let stem = Path::new(&args.model).with_extension("");
+    let output_name = PathBuf::from(format!("{}_sheet.png", stem.display()));
+    let metadata_name = PathBuf::from(format!("{}_sheet.json", stem.display()));
+
+    let scene = dot_vox::load(&args.model).map_err(|e| anyhow!(e))?;
+    let shading = Shading::new(&args.shading, &scene);
+    let model = Brickmap::new(Scene::try_from(&scene)?);
+    let options = args.output.options();
+    let outline = args.outline.outline();
 
-    let sun = vec3(5.0, -3.0, 2.0).normalize();
+    let aabb = model.bounding_box();
+    let frames: Vec<_> = (0..args.frames)
+        .map(|i| {
+            let yaw = i as f32 * 360.0 / args.frames as f32;
+            let camera = args.camera.camera(&aabb, yaw);
+            let pivot = model_pivot(&aabb, &camera);
+            (yaw, camera, pivot, build_view(&model, &camera))
+        })
+        .collect();
 
-    let mut canvas = Image::new(
-        (p2.x - p1.x) as u32 + 1 + BORDER * 2,
-        (p2.y - p1.y) as u32 + 1 + BORDER * 2,
+    // All frames get the extents of all frames relative to the pivot, so
+    // that the bottom center of the model is at the same spot in every
+    // cell.
+    let mut extent = Rect::from_points(
+        frames
+            .iter()
+            .flat_map(|(_, _, pivot, view)| view.keys().map(move |&pos| pos - *pivot))
+            .chain([IVec2::ZERO]),
     );
-    for (pos, (p, idx)) in &view {
-        let color = scene.palette[*idx as usize];
-        let mut color = image::Rgba([color.r, color.g, color.b, 255]);
-        let pos = *pos - p1;
+    if let Some(outline) = &outline {
+        extent = outline.grow(extent);
+    }
+
+    let cells: Vec<_> = frames
+        .iter()
+        .map(|(yaw, camera, pivot, view)| {
+            let rect = Rect::new(extent.min + *pivot, extent.max + *pivot);
+            let mut canvas = Canvas::with_pivot(rect, Some(*pivot), &options);
+            draw_view(
+                &mut canvas,
+                view,
+                camera,
+                outline.as_ref(),
+                options.background,
+                |p, idx| voxel_color(&scene, &model, p, idx, &shading),
+            );
+            (*yaw, canvas.image_pos(*pivot), canvas)
+        })
+        .collect();
+    report_clipped(cells.iter().map(|(_, _, canvas)| canvas.clipped()).sum());
+    let cell = uvec2(cells[0].2.image().width(), cells[0].2.image().height());
+
+    let columns = args.columns.unwrap_or(args.frames).clamp(1, cells.len());
+    let rows = cells.len().div_ceil(columns);
+    let mut canvas = Image::from_pixel(
+        cell.x * columns as u32,
+        cell.y * rows as u32,
+        options.background,
+    );
+
+    let mut metadata = Vec::new();
+    for (i, (yaw, pivot, cell_canvas)) in cells.iter().enumerate() {
+        let offset = cell * uvec2((i % columns) as u32, (i / columns) as u32);
+        let image = cell_canvas.image();
+        image::imageops::replace(&mut canvas, image, offset.x as i64, offset.y as i64);
+
+        metadata.push(json!({
+            "yaw": yaw,
+            "x": offset.x,
+            "y": offset.y,
+            "w": cell.x,
+            "h": cell.y,
+            "pivot": [pivot.x, pivot.y],
+        }));
+    }
+
+    canvas.save(&output_name)?;
+    write_metadata(&metadata_name, &output_name, metadata)?;
+
+    Ok(())
+}
+
+/// Write the JSON file that lists the frames of a sprite sheet image.
+fn write_metadata(path: &Path, image: &Path, frames: Vec<serde_json::Value>) -> Result<()> {
+    let image = image.file_name().unwrap_or_default().to_string_lossy();
+    let metadata = json!({ "image": image, "frames": frames });
+    std::fs::write(path, serde_json::to_string_pretty(&metadata)? + "\n")?;
+    Ok(())
+}
+
+fn animate(args: &AnimateArgs) -> Result<()> {
+    // Keep clear of the image dump writes for the model.
+    let stem = Path::new(&args.model).with_extension("");
+    let output_name = |ext| PathBuf::from(format!("{}_anim.{ext}", stem.display()));
+
+    let scene = dot_vox::load(&args.model).map_err(|e| anyhow!(e))?;
+    let shading = Shading::new(&args.shading, &scene);
+    let options = args.output.options();
+    let outline = args.outline.outline();
+    let keyframes = voxelize::keyframes(&scene);
+
+    let scenes = keyframes
+        .iter()
+        .map(|&f| Ok(Brickmap::new(Scene::at_frame(&scene, f)?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Use one camera for all frames so that things that stay still in the
+    // scene stay still in the animation.
+    let aabb = scenes
+        .iter()
+        .map(|s| s.bounding_box())
+        .reduce(|a, b| BoundingBox::new(a.min.min(b.min), a.max.max(b.max)))
+        .unwrap_or_default();
+    let camera = args.camera.camera(&aabb, 0.0);
+
+    let views: Vec<_> = scenes.iter().map(|s| build_view(s, &camera)).collect();
+    if views.iter().all(|view| view.is_empty()) {
+        return Err(anyhow!("Scene is empty"));
+    }
+
+    // Shared bounds for all frames.
+    let mut rect = Rect::from_points(views.iter().flat_map(|view| view.keys()).copied());
+    if let Some(outline) = &outline {
+        rect = outline.grow(rect);
+    }
+    let pivot = model_pivot(&aabb, &camera);
+
+    // Each keyframe lasts until the next one, the last one lasts a single
+    // frame since the file doesn't give the end of the animation.
+    let durations: Vec<_> = keyframes
+        .iter()
+        .enumerate()
+        .map(|(i, &frame)| {
+            let next = keyframes.get(i + 1).copied().unwrap_or(frame + 1);
+            ((next - frame) as f32 * 1000.0 / args.fps).round() as u32
+        })
+        .collect();
+
+    let canvases: Vec<_> = views
+        .iter()
+        .zip(&scenes)
+        .map(|(view, model)| {
+            let mut canvas = Canvas::with_pivot(rect, Some(pivot), &options);
+            draw_view(
+                &mut canvas,
+                view,
+                &camera,
+                outline.as_ref(),
+                options.background,
+                |p, idx| voxel_color(&scene, model, p, idx, &shading),
+            );
+            canvas
+        })
+        .collect();
+    report_clipped(canvases.iter().map(Canvas::clipped).sum());
+    let images: Vec<_> = canvases.into_iter().map(Canvas::into_image).collect();
+    let cell = uvec2(images[0].width(), images[0].height());
 
-        if shading {
-            let normal = scene.models[0].normal(*p);
-            let light = normal.dot(sun).max(0.4);
-            color = image::Rgba([
-                (color[0] as f32 * light) as u8,
-                (color[1] as f32 * light) as u8,
-                (color[2] as f32 * light) as u8,
-                255,
-            ]);
+    if args.gif {
+        let mut encoder = GifEncoder::new(File::create(output_name("gif"))?);
+        encoder.set_repeat(Repeat::Infinite)?;
+        for (image, &ms) in images.into_iter().zip(&durations) {
+            encoder.encode_frame(Frame::from_parts(
+                image,
+                0,
+                0,
+                Delay::from_numer_denom_ms(ms, 1),
+            ))?;
         }
+        return Ok(());
+    }
 
-        canvas.put_pixel(pos.x as u32 + BORDER, pos.y as u32 + BORDER, color);
+    let mut canvas = Image::new(cell.x * images.len() as u32, cell.y);
+    let mut metadata = Vec::new();
+    for (i, image) in images.iter().enumerate() {
+        let x = cell.x * i as u32;
+        image::imageops::replace(&mut canvas, image, x as i64, 0);
+        metadata.push(json!({
+            "frame": keyframes[i],
+            "duration": durations[i],
+            "x": x,
+            "y": 0,
+            "w": cell.x,
+            "h": cell.y,
+        }));
     }
+    canvas.save(output_name("png"))?;
+    write_metadata(&output_name("json"), &output_name("png"), metadata)?;
 
-    canvas.save(output_name)?;
+    Ok(())
+}
+
+fn report_clipped(clipped: usize) {
+    if clipped > 0 {
+        eprintln!("{clipped} sprite pixels don't fit on the canvas and were left out");
+    }
+}
+
+fn report_palette(palette: &Palette) {
+    if palette.approximated() > 0 {
+        eprintln!(
+            "Approximated {} colors with existing palette colors",
+            palette.approximated()
+        );
+    }
+}
+
+fn carve(args: &CarveArgs) -> Result<()> {
+    let mut prisms = Vec::new();
+    for view in &args.views {
+        let mut camera = view.camera();
+        if let Some(shear) = args.shear {
+            camera.shear = shear;
+        }
+        let image = focus_image(image::open(&view.path)?.into(), view.offset)?;
+        prisms.push(Prism::new(image, camera));
+    }
+
+    let model = match args.consistency {
+        Some(tolerance) => carve::space_carve(&prisms, tolerance)?,
+        None => carve::build_model(&prisms)?,
+    };
+    eprintln!("Model size: {}", model.iter().count());
+
+    let mut palette = Palette::new(Policy::Exact);
+    carve::to_vox(&model, &mut palette, args.dither)?
+        .write_vox(&mut File::create(&args.output)?)?;
+    report_palette(&palette);
 
     Ok(())
 }
 
-fn paint(model_path: &str, camera: Camera, src: &str) -> Result<()> {
-    let mut scene = dot_vox::load(model_path).map_err(|e| anyhow!(e))?;
+/// Sample sprite for painting.
+enum Sprite {
+    /// Sprite pixels map to view pixels 1:1 with the focus point at the
+    /// model origin, same as in carved sprites.
+    Anchored(FocusImage),
+    /// Sprite without an anchor, stretched over the view.
+    Stretched {
+        image: Image,
+        key: Rgba<u8>,
+        bounds: Rect,
+    },
+}
+
+impl Sprite {
+    /// Load the sprite of a view and find its anchor from the view offset
+    /// or from focus pixels.
+    fn load(view: &View) -> Result<Self> {
+        let mut image: Image = image::open(&view.path)?.into();
+        let key = *image.get_pixel(0, 0);
+
+        if view.offset.is_some() {
+            return Ok(Sprite::Anchored(focus_image(image, view.offset)?));
+        }
+        if let Ok(image) = focus_image(image.clone(), None) {
+            return Ok(Sprite::Anchored(image));
+        }
 
-    let mut src: Image = image::open(src)?.into();
-    let color_key = *src.get_pixel(0, 0);
-    // Clear black outline from source.
-    voxelize::clear_outline(&mut src);
+        // Clear black outline from source.
+        voxelize::clear_outline(&mut image);
+        let bounds = Rect::from_image(&image);
+        Ok(Sprite::Stretched { image, key, bounds })
+    }
 
-    let src_bounds = Rect::from_image(&src);
+    /// Sample the sprite color for view pixel `pos`.
+    ///
+    /// `view_bounds` is the extent of the view and `pivot` is the view pixel
+    /// of the model anchor.
+    fn sample(&self, view_bounds: &Rect, pivot: IVec2, pos: IVec2) -> Option<Rgba<u8>> {
+        match self {
+            Sprite::Anchored(image) => image.sample(pos - pivot),
+            Sprite::Stretched { image, key, bounds } => {
+                // Convert between bounding boxes to get the source point.
+                let src_pos = bounds.denormalize(view_bounds.normalize(pos));
+                let color = *image.get_pixel(src_pos.x as u32, src_pos.y as u32);
+                (color != *key).then_some(color)
+            }
+        }
+    }
+}
 
-    let camera = Mat4::from(camera);
+/// Make a focus image of a sprite with its black outline removed.
+///
+/// The focus point is at sprite pixel `offset` if given, the top left pixel
+/// is then the background color of the sprite. Otherwise the focus point is
+/// read from the marker pixels.
+fn focus_image(mut image: Image, offset: Option<IVec2>) -> Result<FocusImage> {
+    let mut image = match offset {
+        Some(offset) => {
+            let key = *image.get_pixel(0, 0);
+            for p in image.pixels_mut() {
+                if *p == key {
+                    *p = carve::DEFAULT_KEY;
+                }
+            }
+            FocusImage::new(image, offset)
+        }
+        None => FocusImage::try_from(image)?,
+    };
+    image.remove_outline(Rgba([0, 0, 0, 255]));
+    Ok(image)
+}
 
-    // Pull camera back, scale up the model so we hit all voxels.
-    let camera =
-        Mat4::from_scale(Vec3::splat(2.0)) * Mat4::from_translation(vec3(0.0, 0.0, -50.0)) * camera;
+/// Find the colors a sprite drawn with `camera` gives to the voxels of
+/// instance `target` of `world` it shows, weighted by how directly the view
+/// faces the voxels.
+///
+/// `anchor` is the world point the sprite is registered with.
+fn sprite_colors(
+    world: &Brickmap<Scene>,
+    target: usize,
+    camera: &Camera,
+    sprite: &Sprite,
+    anchor: Vec3,
+) -> HashMap<IVec3, (f32, Rgba<u8>)> {
+    let towards_viewer = Mat4::from(*camera)
+        .inverse()
+        .transform_vector3(Vec3::Z)
+        .normalize();
 
-    let view = voxelize::build_view(&scene.models[0], &camera);
+    // The view only has the voxels that are visible from the camera, so
+    // voxels occluded by any model in the scene don't get painted.
+    let view = build_view(world, camera);
+    let pivot = voxelize::project(camera, anchor).floor().as_ivec2();
 
-    let view_bounds = Rect::from_points(view.keys().copied());
+    // Stretched sprites cover the whole target model, also where other
+    // models hide it.
+    let view_bounds = match sprite {
+        Sprite::Anchored(_) => Rect::new(IVec2::ZERO, IVec2::ZERO),
+        Sprite::Stretched { .. } => {
+            let instance = &world.body().instances()[target];
+            Rect::from_points(build_view(instance, camera).keys().copied())
+        }
+    };
 
+    // Several view pixels can land on the same voxel, use the most common
+    // color.
+    let mut colors: HashMap<IVec3, HashMap<Rgba<u8>, usize>> = HashMap::new();
     for (pos, (vox_pos, _)) in &view {
-        let vox_pos = vox_pos.as_ivec3();
-        // Convert between bounding boxes to get the source point.
-        let src_pos = src_bounds.denormalize(view_bounds.normalize(*pos));
-        let color = *src.get_pixel(src_pos.x as u32, src_pos.y as u32);
-        if color == color_key {
+        if world.body().instance_at(*vox_pos) != Some(target) {
             continue;
         }
-        scene.set_voxel(0, vox_pos, color);
+        let Some(color) = sprite.sample(&view_bounds, pivot, *pos) else {
+            continue;
+        };
+        *colors
+            .entry(vox_pos.as_ivec3())
+            .or_default()
+            .entry(color)
+            .or_default() += 1;
+    }
+
+    colors
+        .into_iter()
+        .map(|(pos, counts)| {
+            // The view sees the voxel, so keep a small weight even if the
+            // rough normal estimate points away from it or cancels out, as
+            // on both sides of thin features.
+            let facing = world.normal(pos.as_vec3()).dot(towards_viewer).max(0.01);
+            let color = counts
+                .into_iter()
+                .max_by_key(|&(c, n)| (n, c.0))
+                .map(|(c, _)| c)
+                .unwrap();
+            (pos, (facing, color))
+        })
+        .collect()
+}
+
+fn paint(args: &PaintArgs) -> Result<()> {
+    let mut scene = dot_vox::load(&args.model).map_err(|e| anyhow!(e))?;
+
+    let world = Scene::try_from(&scene)?;
+    let target = match &args.target {
+        Some(name) => world
+            .instances()
+            .iter()
+            .position(|instance| instance.name.as_deref() == Some(name))
+            .ok_or_else(|| anyhow!("No model named {name:?} in scene"))?,
+        None if world.instances().is_empty() => return Err(anyhow!("Scene has no models")),
+        None => 0,
+    };
+    let instance = world.instances()[target].clone();
+    // Trace the whole scene so that other models hide the target.
+    let world = Brickmap::new(world);
+
+    // Colors each view gives to the voxels it sees, with the weight of the
+    // view.
+    let mut samples: HashMap<IVec3, Vec<(f32, Rgba<u8>)>> = HashMap::new();
+
+    for view in &args.views {
+        let sprite = Sprite::load(view)?;
+
+        let camera = match sprite {
+            Sprite::Anchored(_) => view.camera(),
+            // Scale up the model so we hit all voxels.
+            Sprite::Stretched { .. } => Camera::from(view.preset).scaled(2.0),
+        };
+
+        // Sprites are registered with the model origin, like the ones carve
+        // builds models from.
+        for (pos, sample) in sprite_colors(&world, target, &camera, &sprite, Vec3::ZERO) {
+            samples.entry(pos).or_default().push(sample);
+        }
+    }
+
+    let colors: Vec<(IVec3, Rgba<u8>)> = samples
+        .into_iter()
+        .filter_map(|(pos, samples)| {
+            let color = if args.blend {
+                let total: f32 = samples.iter().map(|(w, _)| w).sum();
+                let c = samples
+                    .iter()
+                    .map(|&(w, c)| w * vec3(c[0] as f32, c[1] as f32, c[2] as f32))
+                    .sum::<Vec3>()
+                    / total;
+                let c = c.round();
+                Rgba([c.x as u8, c.y as u8, c.z as u8, 255])
+            } else {
+                samples
+                    .iter()
+                    .max_by(|(a, _), (b, _)| a.total_cmp(b))
+                    .map(|&(_, c)| c)
+                    .unwrap()
+            };
+            (color[3] != 0).then(|| (instance.to_local(pos), color))
+        })
+        .collect();
+
+    // Clear the voxels that get painted over, so that palette slots only
+    // they use are free for the new colors.
+    let model_idx = instance.model;
+    let painted: HashSet<_> = colors.iter().map(|(pos, _)| *pos).collect();
+    scene.models[model_idx]
+        .voxels
+        .retain(|v| !painted.contains(&ivec3(v.x as i32, v.y as i32, v.z as i32)));
+
+    let mut palette = Palette::from_vox(&scene, args.palette.into());
+    for &i in &args.lock {
+        palette.lock(i);
+    }
+
+    let mut counts = HashMap::new();
+    for &(_, color) in &colors {
+        *counts.entry(color).or_default() += 1;
+    }
+    palette.fit(&counts);
+
+    for (pos, color) in colors {
+        let idx = if args.dither {
+            palette.index_dithered(color, pos)
+        } else {
+            palette.index(color)
+        };
+        if let Some(idx) = idx {
+            scene.set_voxel_index(model_idx, pos, idx);
+        }
     }
 
-    scene.write_vox(&mut File::create(model_path)?)?;
+    palette.apply(&mut scene);
+    report_palette(&palette);
+
+    scene.write_vox(&mut File::create(&args.model)?)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use voxelize::{Instance, Placement, VoxelGrid};
+
+    /// Plank one voxel thick along y, with `x` cells along x and 4 cells up.
+    fn plank(x: i32) -> VoxelGrid<u8> {
+        let mut grid = VoxelGrid::new(IVec3::ZERO, ivec3(x, 1, 4));
+        for z in 0..4 {
+            for x in 0..x {
+                grid.set(ivec3(x, 0, z), 1);
+            }
+        }
+        grid
+    }
+
+    #[test]
+    fn paint_thin_slab() {
+        // The rough normals of the plank faces cancel out.
+        let world = Brickmap::new(Scene::new(vec![Instance::new(
+            None,
+            0,
+            Placement::default(),
+            plank(4),
+        )]));
+
+        let red = Rgba([255, 0, 0, 255]);
+        let sprite = Sprite::Anchored(FocusImage::new(
+            Image::from_pixel(32, 32, red),
+            ivec2(16, 16),
+        ));
+
+        // Every voxel seen face-on and the end column seen edge-on get
+        // painted.
+        for (camera, n) in [(Camera::SIDE, 16), (Camera::SIDE.rotated(90.0), 4)] {
+            let colors = sprite_colors(&world, 0, &camera, &sprite, Vec3::ZERO);
+            assert_eq!(colors.len(), n);
+            assert!(colors.values().all(|&(w, c)| w > 0.0 && c == red));
+        }
+    }
+
+    #[test]
+    fn paint_behind_other_model() {
+        // Narrower plank in front of the target plank hides half of it.
+        let front = Placement {
+            translation: ivec3(-1, -2, 0),
+            ..Default::default()
+        };
+        let world = Brickmap::new(Scene::new(vec![
+            Instance::new(None, 0, Placement::default(), plank(4)),
+            Instance::new(None, 1, front, plank(2)),
+        ]));
+
+        let red = Rgba([255, 0, 0, 255]);
+        let sprite = Sprite::Anchored(FocusImage::new(
+            Image::from_pixel(32, 32, red),
+            ivec2(16, 16),
+        ));
+
+        let colors = sprite_colors(&world, 0, &Camera::SIDE, &sprite, Vec3::ZERO);
+        assert_eq!(colors.len(), 8);
+        assert!(colors.keys().all(|pos| pos.x >= 0 && pos.y == 0));
+        assert_eq!(
+            sprite_colors(&world, 1, &Camera::SIDE, &sprite, Vec3::ZERO).len(),
+            8
+        );
+    }
+
+    #[test]
+    fn shading_options_need_shading() {
+        let parse = |args: &str| Cli::try_parse_from(args.split(' '));
+        assert!(parse("voxelize dump model.vox").is_ok());
+        for opt in ["--ambient 0.2", "--occlusion 0.5", "--add-ambient"] {
+            assert!(parse(&format!("voxelize dump {opt} model.vox")).is_err());
+            assert!(parse(&format!("voxelize dump --shading {opt} model.vox")).is_ok());
+        }
+    }
+
+    #[test]
+    fn drop_shadow_opacity_is_optional() {
+        let parse = |args: &[&str]| match Cli::try_parse_from(args).unwrap().command {
+            Command::Dump(args) => (args.model, args.drop_shadow),
+            _ => unreachable!(),
+        };
+        // The opacity has to be attached, so the model isn't taken for it.
+        assert_eq!(
+            parse(&["voxelize", "dump", "--drop-shadow", "model.vox"]),
+            ("model.vox".into(), Some(0.5))
+        );
+        assert_eq!(
+            parse(&["voxelize", "dump", "--drop-shadow=0.25", "model.vox"]),
+            ("model.vox".into(), Some(0.25))
+        );
+    }
+
+    #[test]
+    fn outline_levels_are_optional() {
+        let parse = |args: &[&str]| match Cli::try_parse_from(args).unwrap().command {
+            Command::Dump(args) => (args.model, args.outline.selout, args.outline.depth_edges),
+            _ => unreachable!(),
+        };
+        // Levels have to be attached, so the model isn't taken for them.
+        assert_eq!(
+            parse(&[
+                "voxelize",
+                "dump",
+                "--outline",
+                "--selout",
+                "--depth-edges",
+                "model.vox"
+            ]),
+            ("model.vox".into(), Some(0.5), Some(2.0))
+        );
+        assert_eq!(
+            parse(&[
+                "voxelize",
+                "dump",
+                "--outline",
+                "--selout=0.25",
+                "--depth-edges=4",
+                "model.vox"
+            ]),
+            ("model.vox".into(), Some(0.25), Some(4.0))
+        );
+    }
+
+    #[test]
+    fn field_of_view() {
+        assert_eq!(parse_fov("60").unwrap(), 60.0);
+        for fov in ["0", "-30", "180", "200", "NaN"] {
+            assert!(parse_fov(fov).is_err(), "{fov}");
+        }
+    }
+
+    #[test]
+    fn canvas_size() {
+        assert_eq!(parse_size("32, 16").unwrap(), uvec2(32, 16));
+        assert!(parse_size("32,0").is_err());
+        assert!(parse_size("-32,16").is_err());
+        assert!(parse_size("32").is_err());
+    }
+
+    #[test]
+    fn view_fields() {
+        let view = parse_view("sprites/a:b.png:north").unwrap();
+        assert_eq!(view.path, PathBuf::from("sprites/a:b.png"));
+        assert_eq!((view.offset, view.scale), (None, 1.0));
+
+        let view = parse_view("a.png:east:12,-3").unwrap();
+        assert_eq!((view.offset, view.scale), (Some(ivec2(12, -3)), 1.0));
+
+        let view = parse_view("a.png:side:4,5:2").unwrap();
+        assert_eq!((view.offset, view.scale), (Some(ivec2(4, 5)), 2.0));
+
+        let view = parse_view("a.png:side:0.5").unwrap();
+        assert_eq!((view.offset, view.scale), (None, 0.5));
+
+        assert!(parse_view("a.png").is_err());
+        assert!(parse_view("a.png:side:0").is_err());
+        assert!(parse_view("a.png:sideways:1,1").is_err());
+    }
+}
@@ -0,0 +1,224 @@
+use glam::{ivec3, IVec3, Mat3, Vec3};
+
+use crate::Body;
+
+/// Method for estimating surface normals of voxel bodies.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum NormalEstimator {
+    /// Sum of the axis directions with an empty neighbor, see
+    /// `Body::normal`.
+    #[default]
+    Neighbors,
+    /// Gradient of the occupancy field blurred over this radius.
+    Gradient(u32),
+    /// Normal of the plane fitted to the surface voxels within this radius.
+    PlaneFit(u32),
+}
+
+/// Estimate the normal of the voxel at `pos` from the gradient of the
+/// occupancy field blurred with a Gaussian kernel.
+///
+/// Larger radii smooth the surface over more voxels. Falls back to
+/// `Body::normal` if the neighborhood is symmetric, as inside a solid body
+/// or in the middle of a thin plate, and the gradient cancels out.
+pub fn gradient_normal<B: Body + ?Sized>(model: &B, pos: Vec3, radius: u32) -> Vec3 {
+    let r = radius.max(1) as i32;
+    let sigma = r as f32 / 2.0;
+
+    let mut gradient = Vec3::ZERO;
+    let mut total = 0.0;
+    for d in cube(r) {
+        let len2 = d.length_squared();
+        if len2 == 0 || len2 > r * r {
+            continue;
+        }
+        if model.sample(pos + d.as_vec3()).is_some() {
+            let w = (-(len2 as f32) / (2.0 * sigma * sigma)).exp();
+            gradient += d.as_vec3() * w;
+            total += w;
+        }
+    }
+
+    // Rounding errors are left over when the sides cancel out, don't
+    // mistake them for a direction.
+    if gradient.length() <= total * 1e-4 {
+        return model.normal(pos);
+    }
+
+    // Occupancy grows towards the inside, the normal points the other way.
+    (-gradient).normalize()
+}
+
+/// Estimate the normal of the voxel at `pos` by fitting a plane to the
+/// surface voxels around it.
+///
+/// The plane normal is the direction of least variance of the surface voxel
+/// positions, oriented to point out of the body. Falls back to
+/// `Body::normal` when there are too few surface voxels to fit a plane to.
+pub fn plane_fit_normal<B: Body + ?Sized>(model: &B, pos: Vec3, radius: u32) -> Vec3 {
+    let r = radius.max(1) as i32;
+
+    let is_surface = |p: Vec3| {
+        model.sample(p).is_some()
+            && [
+                IVec3::X,
+                IVec3::NEG_X,
+                IVec3::Y,
+                IVec3::NEG_Y,
+                IVec3::Z,
+                IVec3::NEG_Z,
+            ]
+            .iter()
+            .any(|d| model.sample(p + d.as_vec3()).is_none())
+    };
+
+    let points: Vec<Vec3> = cube(r)
+        .filter(|d| d.length_squared() <= r * r)
+        .map(|d| d.as_vec3())
+        .filter(|&d| is_surface(pos + d))
+        .collect();
+
+    if points.len() < 3 {
+        return model.normal(pos);
+    }
+
+    let centroid = points.iter().sum::<Vec3>() / points.len() as f32;
+    let mut covariance = Mat3::ZERO;
+    for p in &points {
+        let d = *p - centroid;
+        covariance += Mat3::from_cols(d * d.x, d * d.y, d * d.z);
+    }
+
+    let (values, vectors) = jacobi_eigen(covariance);
+    let smallest = (0..3)
+        .min_by(|&a, &b| values[a].total_cmp(&values[b]))
+        .unwrap();
+    let normal = vectors.col(smallest).normalize_or_zero();
+
+    let outward = gradient_normal(model, pos, radius);
+    if normal.dot(outward) < 0.0 {
+        -normal
+    } else {
+        normal
+    }
+}
+
+/// Offsets of the cube of cells within `r` steps along every axis.
+fn cube(r: i32) -> impl Iterator<Item = IVec3> {
+    (-r..=r).flat_map(move |z| (-r..=r).flat_map(move |y| (-r..=r).map(move |x| ivec3(x, y, z))))
+}
+
+/// Eigenvalues and eigenvectors of symmetric matrix `m`.
+///
+/// Uses Jacobi rotations, the eigenvectors are the columns of the returned
+/// matrix.
+fn jacobi_eigen(m: Mat3) -> (Vec3, Mat3) {
+    let mut a = m.to_cols_array_2d();
+    let mut v = Mat3::IDENTITY.to_cols_array_2d();
+
+    for _ in 0..32 {
+        // Find the largest off-diagonal element.
+        let (p, q) = [(0, 1), (0, 2), (1, 2)]
+            .into_iter()
+            .max_by(|&(i, j), &(k, l)| a[i][j].abs().total_cmp(&a[k][l].abs()))
+            .unwrap();
+        if a[p][q].abs() < 1e-9 {
+            break;
+        }
+
+        // Rotate it to zero.
+        let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+        let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+        let c = 1.0 / (t * t + 1.0).sqrt();
+        let s = t * c;
+
+        for col in &mut a {
+            let (akp, akq) = (col[p], col[q]);
+            col[p] = c * akp - s * akq;
+            col[q] = s * akp + c * akq;
+        }
+        let (ap, aq) = (a[p], a[q]);
+        a[p] = std::array::from_fn(|k| c * ap[k] - s * aq[k]);
+        a[q] = std::array::from_fn(|k| s * ap[k] + c * aq[k]);
+        for row in &mut v {
+            let (vp, vq) = (row[p], row[q]);
+            row[p] = c * vp - s * vq;
+            row[q] = s * vp + c * vq;
+        }
+    }
+
+    let values = Vec3::new(a[0][0], a[1][1], a[2][2]);
+    // The rotations were accumulated into the rows of the column array, so
+    // the eigenvectors come out as rows of the matrix.
+    (values, Mat3::from_cols_array_2d(&v).transpose())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VoxelGrid;
+    use glam::vec3;
+
+    #[test]
+    fn slope_normals() {
+        // Staircase sloping down along x with two cells wide steps.
+        let mut grid = VoxelGrid::new(IVec3::ZERO, IVec3::splat(16));
+        for z in 0..16 {
+            for y in 0..16 {
+                for x in 0..16 {
+                    if x + 2 * z < 24 {
+                        grid.set(ivec3(x, y, z), 1);
+                    }
+                }
+            }
+        }
+
+        let slope = vec3(1.0, 0.0, 2.0).normalize();
+        // Flat top of a step.
+        let pos = vec3(6.0, 8.0, 8.0);
+
+        // Axis neighbor normals see the staircase steps.
+        let neighbors = grid.estimate_normal(pos, NormalEstimator::Neighbors);
+        assert_eq!(neighbors, Vec3::Z);
+
+        for estimator in [NormalEstimator::Gradient(3), NormalEstimator::PlaneFit(3)] {
+            let n = grid.estimate_normal(pos, estimator);
+            assert!(n.dot(slope) > 0.98, "{estimator:?}: {n}");
+        }
+    }
+
+    #[test]
+    fn symmetric_gradient() {
+        // Plate one voxel thick crossed by a pole, both sides of them are
+        // the same.
+        let mut grid = VoxelGrid::new(IVec3::ZERO, IVec3::splat(16));
+        for y in 0..16 {
+            for x in 0..16 {
+                grid.set(ivec3(x, y, 8), 1);
+            }
+        }
+        for z in 0..16 {
+            grid.set(ivec3(5, 5, z), 1);
+        }
+
+        for radius in 1..6 {
+            for pos in [vec3(10.0, 10.0, 8.0), vec3(5.0, 5.0, 8.0)] {
+                assert_eq!(gradient_normal(&grid, pos, radius), grid.normal(pos));
+            }
+        }
+    }
+
+    #[test]
+    fn jacobi_diagonalizes() {
+        let m = Mat3::from_cols(
+            vec3(4.0, 1.0, 2.0),
+            vec3(1.0, 3.0, 0.5),
+            vec3(2.0, 0.5, 1.0),
+        );
+        let (values, vectors) = jacobi_eigen(m);
+        for i in 0..3 {
+            let v = vectors.col(i);
+            assert!((m * v - v * values[i]).length() < 1e-4);
+        }
+    }
+}
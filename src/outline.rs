@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+
+use glam::{ivec2, IVec2, Mat4, Vec3};
+use image::Rgba;
+
+use crate::{pixel_ray, Camera, Pixel, Rect};
+
+/// Which neighbors of a pixel count as adjacent when tracing outlines.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Connectivity {
+    /// Only horizontal and vertical neighbors, gives outlines without
+    /// corner pixels.
+    #[default]
+    Four,
+    /// Diagonal neighbors too, gives closed outlines.
+    Eight,
+}
+
+impl Connectivity {
+    fn offsets(self) -> &'static [IVec2] {
+        const OFFSETS: [IVec2; 8] = [
+            ivec2(0, -1),
+            ivec2(1, 0),
+            ivec2(0, 1),
+            ivec2(-1, 0),
+            ivec2(1, -1),
+            ivec2(1, 1),
+            ivec2(-1, 1),
+            ivec2(-1, -1),
+        ];
+
+        match self {
+            Connectivity::Four => &OFFSETS[..4],
+            Connectivity::Eight => &OFFSETS,
+        }
+    }
+}
+
+/// Color of outline pixels.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutlineColor {
+    Fixed(Pixel),
+    /// Selective outline, use the color of the sprite pixel the outline is
+    /// next to, darkened to this fraction of its brightness.
+    Selout(f32),
+}
+
+impl Default for OutlineColor {
+    fn default() -> Self {
+        OutlineColor::Fixed(Rgba([0, 0, 0, 255]))
+    }
+}
+
+/// Outline style for rendered views.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Outline {
+    pub color: OutlineColor,
+    pub connectivity: Connectivity,
+    /// Also outline internal edges where the depth along the view ray jumps
+    /// by more than this many voxels between neighboring pixels.
+    pub depth_edges: Option<f32>,
+}
+
+impl Outline {
+    /// Grow the view pixel rectangle `rect` to also fit the outline of the
+    /// view pixels in it.
+    pub fn grow(&self, rect: Rect) -> Rect {
+        // Rects of no points come out inverted, there's nothing to outline.
+        if rect.min.cmpgt(rect.max).any() {
+            return rect;
+        }
+        // Silhouette outlines are one pixel wide.
+        Rect::new(rect.min - IVec2::ONE, rect.max + IVec2::ONE)
+    }
+
+    /// Find the outline pixels for a view from `build_view`.
+    ///
+    /// `color` gives the colors of the view pixels. Silhouette outlines go
+    /// on the empty pixels around the view, internal edges replace the
+    /// pixels on the far side of the depth jump. Draw the returned pixels
+    /// over the rendered view.
+    pub fn pixels<T>(
+        &self,
+        view: &HashMap<IVec2, (Vec3, T)>,
+        camera: &Camera,
+        color: impl Fn(IVec2) -> Pixel,
+    ) -> HashMap<IVec2, Pixel> {
+        let inverse = Mat4::from(*camera).inverse();
+        let depth = |pixel: IVec2| {
+            view.get(&pixel).map(|(p, _)| {
+                let (origin, dir) = pixel_ray(&inverse, pixel);
+                (*p + Vec3::splat(0.5) - origin).dot(dir)
+            })
+        };
+
+        let shade = |from: IVec2| match self.color {
+            OutlineColor::Fixed(c) => c,
+            OutlineColor::Selout(level) => {
+                let c = color(from);
+                let d = |i: usize| (c[i] as f32 * level).round().clamp(0.0, 255.0) as u8;
+                Rgba([d(0), d(1), d(2), c[3]])
+            }
+        };
+
+        // Outline pixels and the sprite pixels they take their color from.
+        let mut ret: HashMap<IVec2, (IVec2, f32)> = HashMap::default();
+
+        for &pixel in view.keys() {
+            let z = depth(pixel).unwrap();
+
+            for &d in self.connectivity.offsets() {
+                let neighbor = pixel + d;
+                match depth(neighbor) {
+                    None => {
+                        // Silhouette edge, when several sprite pixels
+                        // border the same outline pixel, use the one
+                        // nearest to the viewer for selout color.
+                        let nearest = ret
+                            .get(&neighbor)
+                            .map(|&(_, near_z)| z < near_z)
+                            .unwrap_or(true);
+                        if nearest {
+                            ret.insert(neighbor, (pixel, z));
+                        }
+                    }
+                    Some(near_z) => {
+                        if let Some(threshold) = self.depth_edges {
+                            if z - near_z > threshold {
+                                ret.insert(pixel, (pixel, f32::NEG_INFINITY));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        ret.into_iter()
+            .map(|(pixel, (from, _))| (pixel, shade(from)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{build_view, VoxelGrid};
+    use glam::{ivec3, IVec3};
+
+    #[test]
+    fn outline_edges() {
+        // Tall pillar standing on a floor, seen from the top.
+        let mut grid = VoxelGrid::new(IVec3::ZERO, ivec3(5, 5, 6));
+        for y in 0..5 {
+            for x in 0..5 {
+                grid.set(ivec3(x, y, 0), 1);
+            }
+        }
+        for z in 1..6 {
+            grid.set(ivec3(2, 2, z), 2);
+        }
+
+        let camera = Camera::TOP_DOWN;
+        let view = build_view(&grid, &camera);
+        let white = |_| Rgba([255, 255, 255, 255]);
+
+        let outline = Outline::default().pixels(&view, &camera, white);
+        assert!(outline.keys().all(|p| !view.contains_key(p)));
+        // Silhouette of a 5x5 square.
+        assert_eq!(outline.len(), 20);
+        let rect = Outline::default().grow(Rect::from_points(view.keys().copied()));
+        assert!(outline.keys().all(|&p| rect.contains(p)));
+
+        let outline = Outline {
+            connectivity: Connectivity::Eight,
+            ..Default::default()
+        }
+        .pixels(&view, &camera, white);
+        assert_eq!(outline.len(), 24);
+        assert!(outline.keys().all(|&p| rect.contains(p)));
+
+        let outline = Outline {
+            color: OutlineColor::Selout(0.5),
+            depth_edges: Some(2.0),
+            ..Default::default()
+        }
+        .pixels(&view, &camera, white);
+        // The floor around the pillar gets outlined too.
+        assert_eq!(outline.len(), 24);
+        assert!(outline.values().all(|&c| c == Rgba([128, 128, 128, 255])));
+    }
+}
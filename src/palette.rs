@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+
+use dot_vox::DotVoxData;
+use glam::{vec3, IVec3, Vec3};
+use image::Rgba;
+
+use crate::{bayer_threshold, median_cut, Pixel};
+
+/// Number of palette entries voxels can use. VOX files store palette
+/// indices off by one, so the last entry of the 256 color palette is out of
+/// reach.
+pub const PALETTE_SLOTS: usize = 255;
+
+/// How to map colors that aren't in the palette.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Policy {
+    /// Add new colors to free palette slots and only approximate colors
+    /// when the palette is full.
+    #[default]
+    Exact,
+    /// Never change the palette, always use the nearest existing color.
+    Nearest,
+}
+
+/// Palette manager that maps colors into VOX palette indices.
+///
+/// Colors are compared in OKLab space when no exact match is found, so the
+/// approximations follow perceived color differences.
+#[derive(Clone, Debug)]
+pub struct Palette {
+    policy: Policy,
+    colors: Vec<Pixel>,
+    /// Slots that can't be given a new color, either because voxels use
+    /// them or because they're locked.
+    taken: Vec<bool>,
+    locked: Vec<bool>,
+    /// Cached results of mapped colors.
+    mapping: HashMap<Pixel, u8>,
+    added: usize,
+    approximated: usize,
+}
+
+impl Palette {
+    /// Create a palette with all slots free.
+    pub fn new(policy: Policy) -> Self {
+        Palette {
+            policy,
+            colors: vec![Rgba([0, 0, 0, 255]); PALETTE_SLOTS],
+            taken: vec![false; PALETTE_SLOTS],
+            locked: vec![false; PALETTE_SLOTS],
+            mapping: HashMap::new(),
+            added: 0,
+            approximated: 0,
+        }
+    }
+
+    /// Use the palette of a VOX file.
+    ///
+    /// Slots that aren't used by any voxel in any model are free for new
+    /// colors.
+    pub fn from_vox(data: &DotVoxData, policy: Policy) -> Self {
+        let mut ret = Palette::new(policy);
+        for (i, c) in data.palette.iter().take(PALETTE_SLOTS).enumerate() {
+            ret.colors[i] = Rgba([c.r, c.g, c.b, c.a]);
+        }
+        for voxel in data.models.iter().flat_map(|m| &m.voxels) {
+            if let Some(taken) = ret.taken.get_mut(voxel.i as usize) {
+                *taken = true;
+            }
+        }
+        ret
+    }
+
+    /// Keep the color of palette slot `index` from being changed.
+    pub fn lock(&mut self, index: u8) {
+        if let Some(locked) = self.locked.get_mut(index as usize) {
+            *locked = true;
+            self.taken[index as usize] = true;
+        }
+    }
+
+    pub fn is_locked(&self, index: u8) -> bool {
+        self.locked.get(index as usize).copied().unwrap_or(false)
+    }
+
+    /// Color of palette slot `index`.
+    pub fn color(&self, index: u8) -> Pixel {
+        self.colors[index as usize]
+    }
+
+    /// Find the palette index for `color`.
+    ///
+    /// Returns `None` for fully transparent colors, they mean no voxel.
+    pub fn index(&mut self, color: Pixel) -> Option<u8> {
+        if color[3] == 0 {
+            return None;
+        }
+        if let Some(&idx) = self.mapping.get(&color) {
+            return Some(idx);
+        }
+
+        let exact = self.colors.iter().position(|&c| c == color);
+        let free = || self.taken.iter().position(|&t| !t);
+
+        let idx = match (exact, self.policy) {
+            (Some(i), _) => i,
+            (None, Policy::Exact) if free().is_some() => {
+                let i = free().unwrap();
+                self.colors[i] = color;
+                self.added += 1;
+                i
+            }
+            _ => {
+                self.approximated += 1;
+                self.nearest(color)
+            }
+        };
+
+        self.taken[idx] = true;
+        self.mapping.insert(color, idx as u8);
+        Some(idx as u8)
+    }
+
+    /// Find the palette index for `color` at voxel `pos`.
+    ///
+    /// Colors that don't have an exact match are dithered between the two
+    /// nearest palette colors with an ordered dithering pattern.
+    pub fn index_dithered(&mut self, color: Pixel, pos: IVec3) -> Option<u8> {
+        let idx = self.index(color)?;
+        let first = self.colors[idx as usize];
+        if first == color {
+            return Some(idx);
+        }
+
+        let second = self.nearest_except(color, idx as usize);
+        let (a, b) = (oklab(first), oklab(self.colors[second]));
+        if a == b {
+            return Some(idx);
+        }
+
+        // How far along from the nearest color to the second nearest one
+        // the color is.
+        let t = ((oklab(color) - a).dot(b - a) / a.distance_squared(b)).clamp(0.0, 1.0);
+        if bayer_threshold(pos) < t {
+            self.taken[second] = true;
+            Some(second as u8)
+        } else {
+            Some(idx)
+        }
+    }
+
+    /// Make room for `colors` before mapping them.
+    ///
+    /// `colors` maps every color to how many voxels use it. With the `Exact`
+    /// policy, if the new colors don't fit in the free slots, they're reduced
+    /// with median cut into the set that best fits the free slots, instead
+    /// of the first colors that come along filling the palette.
+    pub fn fit(&mut self, colors: &HashMap<Pixel, usize>) {
+        if self.policy != Policy::Exact {
+            return;
+        }
+
+        let new: HashMap<Pixel, usize> = colors
+            .iter()
+            .filter(|(c, _)| c[3] != 0 && !self.colors.contains(c))
+            .map(|(&c, &n)| (c, n))
+            .collect();
+        let free = self.taken.iter().filter(|&&t| !t).count();
+        if new.len() <= free {
+            return;
+        }
+
+        for color in median_cut(&new, free) {
+            self.index(color);
+        }
+    }
+
+    /// Find the palette index with the color closest to `color` without
+    /// changing the palette.
+    pub fn nearest_index(&self, color: Pixel) -> u8 {
+        self.nearest(color) as u8
+    }
+
+    fn nearest(&self, color: Pixel) -> usize {
+        self.nearest_except(color, usize::MAX)
+    }
+
+    fn nearest_except(&self, color: Pixel, skip: usize) -> usize {
+        let target = oklab(color);
+        let alpha = color[3] as f32 / 255.0;
+
+        (0..PALETTE_SLOTS)
+            .filter(|&i| i != skip)
+            .min_by(|&a, &b| {
+                let d = |i: usize| {
+                    let c = self.colors[i];
+                    oklab(c).distance_squared(target) + (c[3] as f32 / 255.0 - alpha).powi(2)
+                };
+                d(a).total_cmp(&d(b))
+            })
+            .unwrap_or(0)
+    }
+
+    /// Number of new colors added to the palette.
+    pub fn added(&self) -> usize {
+        self.added
+    }
+
+    /// Number of distinct colors that were mapped to a different palette
+    /// color.
+    pub fn approximated(&self) -> usize {
+        self.approximated
+    }
+
+    /// Write the palette colors into a VOX file.
+    pub fn apply(&self, data: &mut DotVoxData) {
+        data.palette.resize(
+            256,
+            dot_vox::Color {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 255,
+            },
+        );
+        for (i, c) in self.colors.iter().enumerate() {
+            data.palette[i] = dot_vox::Color {
+                r: c[0],
+                g: c[1],
+                b: c[2],
+                a: c[3],
+            };
+        }
+    }
+}
+
+/// Convert sRGB color into the OKLab perceptual color space.
+pub fn oklab(color: Pixel) -> Vec3 {
+    fn linear(c: u8) -> f32 {
+        let c = c as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    }
+
+    let (r, g, b) = (linear(color[0]), linear(color[1]), linear(color[2]));
+
+    let l = (0.412_221_46 * r + 0.536_332_55 * g + 0.051_445_995 * b).cbrt();
+    let m = (0.211_903_5 * r + 0.680_699_5 * g + 0.107_396_96 * b).cbrt();
+    let s = (0.088_302_46 * r + 0.281_718_85 * g + 0.629_978_7 * b).cbrt();
+
+    vec3(
+        0.210_454_26 * l + 0.793_617_8 * m - 0.004_072_047 * s,
+        1.977_998_5 * l - 2.428_592_2 * m + 0.450_593_7 * s,
+        0.025_904_037 * l + 0.782_771_77 * m - 0.808_675_77 * s,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_policies() {
+        let mut palette = Palette::new(Policy::Exact);
+        palette.lock(0);
+
+        let red = Rgba([255, 0, 0, 255]);
+        assert_eq!(palette.index(red), Some(1));
+        assert_eq!(palette.index(red), Some(1));
+        assert_eq!(palette.index(Rgba([0, 0, 0, 0])), None);
+        assert_eq!(palette.color(0), Rgba([0, 0, 0, 255]));
+
+        // Fill up the palette.
+        for i in 0..(PALETTE_SLOTS - 2) {
+            assert!(palette.index(Rgba([0, i as u8, 255, 255])).is_some());
+        }
+        assert_eq!(palette.added(), PALETTE_SLOTS - 1);
+        assert_eq!(palette.approximated(), 0);
+
+        // Full palette falls back to nearest color.
+        assert_eq!(palette.index(Rgba([250, 10, 0, 255])), Some(1));
+        assert_eq!(palette.approximated(), 1);
+
+        // Locked slot is never overwritten.
+        assert!(palette.is_locked(0));
+        assert_eq!(palette.color(0), Rgba([0, 0, 0, 255]));
+
+        // Fitting a large set of colors into a nearly full palette.
+        let mut palette = Palette::new(Policy::Exact);
+        let ramp: HashMap<Pixel, usize> = (0..=255u8)
+            .flat_map(|i| [(Rgba([i, 0, 0, 255]), 1), (Rgba([0, i, 0, 255]), 1)])
+            .collect();
+        palette.fit(&ramp);
+        assert_eq!(palette.added(), PALETTE_SLOTS);
+        for &c in ramp.keys() {
+            palette.index(c);
+        }
+        assert_eq!(palette.added(), PALETTE_SLOTS);
+        assert_eq!(palette.approximated(), ramp.len() - PALETTE_SLOTS);
+
+        let mut nearest = Palette::new(Policy::Nearest);
+        assert_eq!(nearest.index(red), Some(0));
+        assert_eq!(nearest.added(), 0);
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use glam::{IVec3, Vec3};
+
+use crate::{oklab, Pixel};
+
+/// Reduce a set of colors to at most `n` colors with median cut in OKLab
+/// space.
+///
+/// `colors` maps every color to how many times it is used, frequent colors
+/// weigh more when picking the palette. The returned colors are picked from
+/// the input colors, so exact colors of the source art are preserved where
+/// possible.
+pub fn median_cut(colors: &HashMap<Pixel, usize>, n: usize) -> Vec<Pixel> {
+    let mut entries: Vec<(Vec3, Pixel, usize)> =
+        colors.iter().map(|(&c, &w)| (oklab(c), c, w)).collect();
+    // Keep results deterministic regardless of hash order.
+    entries.sort_by_key(|&(_, c, _)| c.0);
+
+    if entries.len() <= n {
+        return entries.into_iter().map(|(_, c, _)| c).collect();
+    }
+    if n == 0 {
+        return Vec::new();
+    }
+
+    let mut boxes = vec![entries];
+    while boxes.len() < n {
+        // Split the box with the widest spread of colors.
+        let Some((i, axis, _)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .map(|(i, b)| {
+                let (min, max) = extent(b);
+                let size = max - min;
+                let axis = (0..3).max_by(|&a, &b| size[a].total_cmp(&size[b])).unwrap();
+                (i, axis, size[axis])
+            })
+            .max_by(|a, b| a.2.total_cmp(&b.2))
+        else {
+            break;
+        };
+
+        let mut b = boxes.swap_remove(i);
+        b.sort_by(|p, q| p.0[axis].total_cmp(&q.0[axis]));
+
+        // Split at the weighted median, but leave at least one color on
+        // both sides.
+        let total: usize = b.iter().map(|e| e.2).sum();
+        let mut acc = 0;
+        let mut split = 1;
+        for (j, e) in b.iter().enumerate() {
+            acc += e.2;
+            if acc * 2 >= total {
+                split = j + 1;
+                break;
+            }
+        }
+        let split = split.clamp(1, b.len() - 1);
+
+        let rest = b.split_off(split);
+        boxes.push(b);
+        boxes.push(rest);
+    }
+
+    boxes.iter().map(|b| representative(b)).collect()
+}
+
+fn extent(entries: &[(Vec3, Pixel, usize)]) -> (Vec3, Vec3) {
+    entries.iter().fold(
+        (Vec3::INFINITY, Vec3::NEG_INFINITY),
+        |(min, max), &(p, _, _)| (min.min(p), max.max(p)),
+    )
+}
+
+/// Pick the color closest to the weighted mean of the box.
+fn representative(entries: &[(Vec3, Pixel, usize)]) -> Pixel {
+    let total: usize = entries.iter().map(|e| e.2).sum();
+    let mean = entries.iter().map(|&(p, _, w)| p * w as f32).sum::<Vec3>() / total.max(1) as f32;
+
+    entries
+        .iter()
+        .min_by(|a, b| {
+            a.0.distance_squared(mean)
+                .total_cmp(&b.0.distance_squared(mean))
+        })
+        .unwrap()
+        .1
+}
+
+/// 4x4 Bayer matrix for ordered dithering.
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Ordered dithering threshold in [0, 1) for a voxel.
+///
+/// The 2D pattern is sheared through the volume so that faces along every
+/// axis go through all the thresholds.
+pub fn bayer_threshold(pos: IVec3) -> f32 {
+    let u = (pos.x + pos.z).rem_euclid(4) as usize;
+    let v = (pos.y - pos.z).rem_euclid(4) as usize;
+    (BAYER[v][u] as f32 + 0.5) / 16.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn median_cut_reduces_colors() {
+        let mut colors = HashMap::new();
+        for i in 0..=255u8 {
+            colors.insert(Rgba([i, 0, 0, 255]), 1);
+            colors.insert(Rgba([0, 0, i, 255]), 1);
+        }
+        // A heavily used color should survive.
+        colors.insert(Rgba([0, 200, 0, 255]), 1000);
+
+        let palette = median_cut(&colors, 16);
+        assert_eq!(palette.len(), 16);
+        assert!(palette.contains(&Rgba([0, 200, 0, 255])));
+        // Both red and blue ramps get represented.
+        assert!(palette.iter().any(|c| c[0] > 128 && c[2] == 0));
+        assert!(palette.iter().any(|c| c[2] > 128 && c[0] == 0));
+
+        // Small sets pass through as is.
+        assert_eq!(median_cut(&colors, 1000).len(), colors.len());
+    }
+}
@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+
+use glam::{ivec2, uvec2, vec2, IVec2, UVec2, Vec2, Vec3};
+use image::{ImageBuffer, Rgba};
+
+use crate::{build_view, Body, Camera, Image, Pixel, Rect};
+
+/// Empty space around the model in view pixels.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Padding {
+    pub left: u32,
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+}
+
+impl Padding {
+    /// Same padding on every side.
+    pub const fn uniform(n: u32) -> Self {
+        Padding {
+            left: n,
+            top: n,
+            right: n,
+            bottom: n,
+        }
+    }
+
+    fn min(&self) -> IVec2 {
+        ivec2(self.left as i32, self.top as i32)
+    }
+
+    fn size(&self) -> IVec2 {
+        ivec2(
+            (self.left + self.right) as i32,
+            (self.top + self.bottom) as i32,
+        )
+    }
+}
+
+/// Point of the canvas the view is aligned to.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    #[default]
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    /// Position of the anchor point as a fraction of canvas width and
+    /// height.
+    fn fraction(self) -> Vec2 {
+        use Anchor::*;
+        match self {
+            TopLeft => vec2(0.0, 0.0),
+            Top => vec2(0.5, 0.0),
+            TopRight => vec2(1.0, 0.0),
+            Left => vec2(0.0, 0.5),
+            Center => vec2(0.5, 0.5),
+            Right => vec2(1.0, 0.5),
+            BottomLeft => vec2(0.0, 1.0),
+            Bottom => vec2(0.5, 1.0),
+            BottomRight => vec2(1.0, 1.0),
+        }
+    }
+}
+
+/// Output settings for rendering views into images.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RenderOptions {
+    pub padding: Padding,
+    /// Color of the pixels that don't show the model. Use a transparent
+    /// color for an alpha channel background or an opaque one for a color
+    /// key.
+    pub background: Pixel,
+    /// Draw every view pixel as a square of this many image pixels.
+    pub scale: u32,
+    /// Fixed canvas size in view pixels, including the padding. The canvas
+    /// fits the model if not set.
+    pub size: Option<UVec2>,
+    /// Where the model goes when the canvas is larger than the model.
+    pub anchor: Anchor,
+    /// Round the canvas size up to powers of two.
+    pub power_of_two: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            padding: Padding::default(),
+            background: Rgba([0, 0, 0, 0]),
+            scale: 1,
+            size: None,
+            anchor: Anchor::default(),
+            power_of_two: false,
+        }
+    }
+}
+
+/// Render the model into an image.
+///
+/// `color` gives the color for the view pixels from the hit cell and its
+/// value.
+pub fn render<T>(
+    model: &dyn Body<Value = T>,
+    camera: &Camera,
+    color: impl Fn(Vec3, T) -> Pixel,
+    options: &RenderOptions,
+) -> Image {
+    render_view(build_view(model, camera), color, options)
+}
+
+/// Draw a view from `build_view` into an image.
+///
+/// Same as `render`, for views that are already built.
+pub fn render_view<T>(
+    view: HashMap<IVec2, (Vec3, T)>,
+    color: impl Fn(Vec3, T) -> Pixel,
+    options: &RenderOptions,
+) -> Image {
+    let mut canvas = Canvas::new(Rect::from_points(view.keys().copied()), options);
+    for (pixel, (pos, val)) in view {
+        canvas.put(pixel, color(pos, val));
+    }
+    canvas.into_image()
+}
+
+/// Image that view pixels are drawn on.
+///
+/// Maps positions from the image space of `project` to image pixels, so
+/// several layers rendered from the same view can be kept aligned.
+#[derive(Clone, Debug)]
+pub struct Canvas {
+    image: Image,
+    /// View pixel at the top left corner of the image.
+    origin: IVec2,
+    scale: u32,
+    /// Number of pixels drawn outside the canvas.
+    clipped: usize,
+}
+
+impl Canvas {
+    /// Create a canvas that shows the view pixels in `rect`.
+    pub fn new(rect: Rect, options: &RenderOptions) -> Self {
+        Self::with_pivot(rect, None, options)
+    }
+
+    /// Create a canvas that shows the view pixels in `rect` with view pixel
+    /// `pivot` at the anchor point of the canvas.
+    ///
+    /// The pivot is only used with a fixed canvas size. Views rendered with
+    /// pivots at the same model point then line up with each other, parts
+    /// of the model past the anchor point go into the padding. Otherwise the
+    /// whole `rect` is aligned with the anchor.
+    pub fn with_pivot(rect: Rect, pivot: Option<IVec2>, options: &RenderOptions) -> Self {
+        // Rects of no points come out inverted.
+        let rect = if rect.min.cmpgt(rect.max).any() {
+            Rect::new(IVec2::ZERO, IVec2::ZERO)
+        } else {
+            rect
+        };
+        let padding = options.padding;
+        let anchor = options.anchor.fraction();
+        let content = (rect.max - rect.min).max(IVec2::ZERO);
+
+        let mut size = match options.size {
+            Some(size) => size,
+            None => (content + padding.size()).as_uvec2(),
+        };
+        if options.power_of_two {
+            size = uvec2(size.x.next_power_of_two(), size.y.next_power_of_two());
+        }
+
+        // Space inside the padding.
+        let inner = (size.as_ivec2() - padding.size()).max(IVec2::ZERO);
+
+        // Canvas position of the top left corner of `rect`.
+        let offset = padding.min()
+            + match (options.size, pivot) {
+                (Some(_), Some(pivot)) => {
+                    let point = (inner.as_vec2() * anchor).floor().as_ivec2();
+                    point.min(inner - IVec2::ONE).max(IVec2::ZERO) - (pivot - rect.min)
+                }
+                _ => ((inner - content).as_vec2() * anchor).floor().as_ivec2(),
+            };
+
+        let scale = options.scale.max(1);
+        let size = size * scale;
+
+        Canvas {
+            image: Image::from_pixel(size.x, size.y, options.background),
+            origin: rect.min - offset,
+            scale,
+            clipped: 0,
+        }
+    }
+
+    /// Create an empty canvas with the same layout, for an extra layer of
+    /// the view.
+    pub fn layer(&self, background: Pixel) -> Self {
+        Canvas {
+            image: Image::from_pixel(self.image.width(), self.image.height(), background),
+            clipped: 0,
+            ..*self
+        }
+    }
+
+    /// View pixels covered by the canvas.
+    pub fn rect(&self) -> Rect {
+        let size = ivec2(self.image.width() as i32, self.image.height() as i32);
+        Rect::new(self.origin, self.origin + size / self.scale as i32)
+    }
+
+    /// Color of view pixel `pixel`, `None` if it's outside the canvas.
+    pub fn get(&self, pixel: IVec2) -> Option<Pixel> {
+        if !self.rect().contains(pixel) {
+            return None;
+        }
+        let pos = self.image_pos(pixel).as_uvec2();
+        Some(*self.image.get_pixel(pos.x, pos.y))
+    }
+
+    /// Draw view pixel `pixel`, pixels outside the canvas are ignored and
+    /// counted in `clipped`.
+    pub fn put(&mut self, pixel: IVec2, color: Pixel) {
+        if !self.rect().contains(pixel) {
+            self.clipped += 1;
+            return;
+        }
+        let pos = self.image_pos(pixel).as_uvec2();
+        for y in 0..self.scale {
+            for x in 0..self.scale {
+                self.image.put_pixel(pos.x + x, pos.y + y, color);
+            }
+        }
+    }
+
+    /// Image pixel at the top left corner of the square view pixel `pixel`
+    /// is drawn as.
+    ///
+    /// Can be outside the image, use it to find where model points like the
+    /// pivot end up in the image.
+    pub fn image_pos(&self, pixel: IVec2) -> IVec2 {
+        (pixel - self.origin) * self.scale as i32
+    }
+
+    /// Lay out an image of view pixels starting at `min`, like the layers
+    /// `RenderBuffers` exports, the same way as the canvas.
+    ///
+    /// Pixels outside the canvas are dropped and the rest of the result is
+    /// left zero, so layers of any pixel type line up with the canvas image.
+    pub fn align<P: image::Pixel>(
+        &self,
+        min: IVec2,
+        image: &ImageBuffer<P, Vec<P::Subpixel>>,
+    ) -> ImageBuffer<P, Vec<P::Subpixel>> {
+        let mut ret = ImageBuffer::new(self.image.width(), self.image.height());
+        let rect = self.rect();
+        for (x, y, color) in image.enumerate_pixels() {
+            let pixel = min + ivec2(x as i32, y as i32);
+            if !rect.contains(pixel) {
+                continue;
+            }
+            let pos = self.image_pos(pixel).as_uvec2();
+            for dy in 0..self.scale {
+                for dx in 0..self.scale {
+                    ret.put_pixel(pos.x + dx, pos.y + dy, *color);
+                }
+            }
+        }
+        ret
+    }
+
+    /// Number of pixels drawn outside the canvas, when a fixed canvas size
+    /// is too small for the view.
+    pub fn clipped(&self) -> usize {
+        self.clipped
+    }
+
+    /// The image drawn so far.
+    pub fn image(&self) -> &Image {
+        &self.image
+    }
+
+    /// Take the finished image out of the canvas.
+    pub fn into_image(self) -> Image {
+        self.image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VoxelGrid;
+    use glam::{ivec3, IVec3};
+
+    #[test]
+    fn render_image() {
+        let mut grid = VoxelGrid::new(IVec3::ZERO, ivec3(3, 2, 1));
+        for y in 0..2 {
+            for x in 0..3 {
+                grid.set(ivec3(x, y, 0), 1);
+            }
+        }
+
+        let camera = Camera::TOP_DOWN;
+        let red = Rgba([255, 0, 0, 255]);
+        let key = Rgba([255, 0, 255, 255]);
+
+        let image = render(&grid, &camera, |_, _| red, &Default::default());
+        assert_eq!(image.dimensions(), (3, 2));
+        assert!(image.pixels().all(|&p| p == red));
+
+        let options = RenderOptions {
+            padding: Padding::uniform(1),
+            background: key,
+            scale: 2,
+            ..Default::default()
+        };
+        let image = render(&grid, &camera, |_, _| red, &options);
+        assert_eq!(image.dimensions(), (10, 8));
+        assert_eq!(image.get_pixel(0, 0), &key);
+        assert_eq!(image.get_pixel(2, 2), &red);
+        assert_eq!(image.pixels().filter(|&&p| p == red).count(), 24);
+    }
+
+    #[test]
+    fn canvas_layout() {
+        let rect = Rect::new(ivec2(10, 20), ivec2(15, 23));
+
+        let options = RenderOptions {
+            padding: Padding {
+                left: 1,
+                top: 2,
+                right: 3,
+                bottom: 4,
+            },
+            power_of_two: true,
+            ..Default::default()
+        };
+        let canvas = Canvas::new(rect, &options);
+        assert_eq!(canvas.image().dimensions(), (16, 16));
+        // Content is centered inside the padding.
+        assert_eq!(canvas.rect().min, ivec2(10 - 1 - 3, 20 - 2 - 3));
+
+        // Views with different extents line up at the pivot on a fixed
+        // canvas.
+        let options = RenderOptions {
+            size: Some(uvec2(32, 32)),
+            anchor: Anchor::Bottom,
+            padding: Padding::uniform(2),
+            ..Default::default()
+        };
+        let red = Rgba([255, 0, 0, 255]);
+        for (rect, pivot) in [
+            (rect, ivec2(12, 22)),
+            (Rect::new(ivec2(-8, -8), ivec2(0, 2)), ivec2(-4, 1)),
+        ] {
+            let mut canvas = Canvas::with_pivot(rect, Some(pivot), &options);
+            canvas.put(pivot, red);
+            assert_eq!(canvas.image_pos(pivot), ivec2(16, 29));
+            assert_eq!(canvas.image().dimensions(), (32, 32));
+            // Below the anchor only the padding is left.
+            canvas.put(pivot + ivec2(0, 3), red);
+            assert_eq!(canvas.clipped(), 1);
+            assert_eq!(canvas.image().get_pixel(16, 29), &red);
+        }
+    }
+
+    #[test]
+    fn canvas_align() {
+        let rect = Rect::new(ivec2(10, 20), ivec2(13, 22));
+        let options = RenderOptions {
+            padding: Padding::uniform(1),
+            scale: 2,
+            ..Default::default()
+        };
+        let canvas = Canvas::new(rect, &options);
+
+        // Layer with a pixel past the canvas edge.
+        let mut layer = ImageBuffer::<image::Luma<u16>, _>::new(5, 2);
+        layer.put_pixel(1, 0, image::Luma([1000]));
+        layer.put_pixel(4, 1, image::Luma([2000]));
+
+        let aligned = canvas.align(rect.min, &layer);
+        assert_eq!(aligned.dimensions(), canvas.image().dimensions());
+        assert_eq!(canvas.image_pos(ivec2(11, 20)), ivec2(4, 2));
+        for (x, y) in [(4, 2), (5, 2), (4, 3), (5, 3)] {
+            assert_eq!(aligned.get_pixel(x, y), &image::Luma([1000]));
+        }
+        assert_eq!(aligned.pixels().filter(|p| p.0[0] != 0).count(), 4);
+    }
+}
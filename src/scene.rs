@@ -0,0 +1,511 @@
+use anyhow::{bail, Result};
+use dot_vox::{DotVoxData, SceneNode};
+use glam::{ivec3, IVec3, Mat3, Vec3};
+
+use crate::{Body, BoundingBox, VoxelGrid};
+
+/// Placement of a model in world space.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Placement {
+    /// Signed permutation matrix that orients the model.
+    pub rotation: Mat3,
+    pub translation: IVec3,
+}
+
+impl Default for Placement {
+    fn default() -> Self {
+        Placement {
+            rotation: Mat3::IDENTITY,
+            translation: IVec3::ZERO,
+        }
+    }
+}
+
+impl Placement {
+    /// Read the placement of a transform node frame.
+    fn from_frame(frame: &dot_vox::Frame) -> Self {
+        let rotation = frame
+            .orientation()
+            .map(|r| Mat3::from_cols_array_2d(&r.to_cols_array_2d()))
+            .unwrap_or(Mat3::IDENTITY);
+        let translation = frame
+            .position()
+            .map(|p| ivec3(p.x, p.y, p.z))
+            .unwrap_or_default();
+
+        Placement {
+            rotation,
+            translation,
+        }
+    }
+
+    /// Apply a child placement inside this one.
+    fn then(&self, child: &Placement) -> Placement {
+        Placement {
+            rotation: self.rotation * child.rotation,
+            translation: self.apply(child.translation),
+        }
+    }
+
+    fn apply(&self, pos: IVec3) -> IVec3 {
+        (self.rotation * pos.as_vec3()).round().as_ivec3() + self.translation
+    }
+
+    fn unapply(&self, pos: IVec3) -> IVec3 {
+        (self.rotation.transpose() * (pos - self.translation).as_vec3())
+            .round()
+            .as_ivec3()
+    }
+}
+
+/// A model placed in the world by the scene graph.
+#[derive(Clone, Debug)]
+pub struct Instance {
+    /// Name given to the model's transform node in the editor.
+    pub name: Option<String>,
+    /// Index of the model in `DotVoxData::models`.
+    pub model: usize,
+    pub placement: Placement,
+    grid: VoxelGrid<u8>,
+}
+
+impl Instance {
+    pub fn new(
+        name: Option<String>,
+        model: usize,
+        placement: Placement,
+        grid: VoxelGrid<u8>,
+    ) -> Self {
+        Instance {
+            name,
+            model,
+            placement,
+            grid,
+        }
+    }
+
+    /// Point the model is rotated around and placed at, MagicaVoxel puts it
+    /// at the center of the model.
+    fn pivot(&self) -> IVec3 {
+        self.grid.origin() + self.grid.size() / 2
+    }
+
+    /// Convert a model space cell into world space.
+    pub fn to_world(&self, pos: IVec3) -> IVec3 {
+        self.placement.apply(pos - self.pivot())
+    }
+
+    /// Convert a world space cell into model space.
+    pub fn to_local(&self, pos: IVec3) -> IVec3 {
+        self.placement.unapply(pos) + self.pivot()
+    }
+
+    /// The model voxels in model space.
+    pub fn grid(&self) -> &VoxelGrid<u8> {
+        &self.grid
+    }
+}
+
+impl Body for Instance {
+    type Value = u8;
+
+    fn sample(&self, pos: Vec3) -> Option<Self::Value> {
+        self.grid
+            .get(self.to_local(pos.floor().as_ivec3()))
+            .copied()
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        let (origin, size) = (self.grid.origin(), self.grid.size());
+        if size.min_element() <= 0 {
+            return Default::default();
+        }
+
+        // Transform the outermost cells and then extend to cover them.
+        let a = self.to_world(origin);
+        let b = self.to_world(origin + size - IVec3::ONE);
+        BoundingBox::new(a.min(b).as_vec3(), (a.max(b) + IVec3::ONE).as_vec3())
+    }
+}
+
+/// All the models of a VOX file composed into world space.
+#[derive(Clone, Debug, Default)]
+pub struct Scene {
+    instances: Vec<Instance>,
+}
+
+impl Scene {
+    pub fn new(instances: Vec<Instance>) -> Self {
+        Scene { instances }
+    }
+
+    pub fn instances(&self) -> &[Instance] {
+        &self.instances
+    }
+
+    /// Find a model instance by its name.
+    pub fn find(&self, name: &str) -> Option<&Instance> {
+        self.instances
+            .iter()
+            .find(|instance| instance.name.as_deref() == Some(name))
+    }
+
+    /// Index of the instance that `sample` takes the voxel at `pos` from.
+    pub fn instance_at(&self, pos: Vec3) -> Option<usize> {
+        self.instances
+            .iter()
+            .position(|instance| instance.sample(pos).is_some())
+    }
+}
+
+impl Scene {
+    /// Compose the scene as it is at animation frame `frame`.
+    ///
+    /// Shape nodes show the model and transform nodes use the placement of
+    /// the latest keyframe at or before `frame`. Placements are not
+    /// interpolated between keyframes. Fails if the scene graph has a cycle.
+    pub fn at_frame(data: &DotVoxData, frame: u32) -> Result<Self> {
+        let mut instances = Vec::new();
+
+        if data.scenes.is_empty() {
+            // Files without a scene graph keep the models at their voxel
+            // coordinates.
+            for (i, model) in data.models.iter().enumerate() {
+                let grid = VoxelGrid::from(model);
+                let placement = Placement {
+                    translation: grid.origin() + grid.size() / 2,
+                    ..Default::default()
+                };
+                instances.push(Instance::new(None, i, placement, grid));
+            }
+        } else {
+            collect(
+                data,
+                0,
+                frame,
+                &Placement::default(),
+                None,
+                &mut Vec::new(),
+                &mut instances,
+            )?;
+        }
+
+        Ok(Scene { instances })
+    }
+}
+
+impl TryFrom<&DotVoxData> for Scene {
+    type Error = anyhow::Error;
+
+    fn try_from(data: &DotVoxData) -> Result<Self> {
+        Scene::at_frame(data, 0)
+    }
+}
+
+/// List the animation frames where something in the scene changes, in
+/// ascending order.
+///
+/// Scenes without animation have a single keyframe 0.
+pub fn keyframes(data: &DotVoxData) -> Vec<u32> {
+    let mut ret = vec![0];
+    for node in &data.scenes {
+        match node {
+            SceneNode::Transform { frames, .. } => {
+                ret.extend(frames.iter().filter_map(|f| f.frame_index()))
+            }
+            SceneNode::Shape { models, .. } => {
+                ret.extend(models.iter().filter_map(|m| m.frame_index()))
+            }
+            SceneNode::Group { .. } => {}
+        }
+    }
+    ret.sort_unstable();
+    ret.dedup();
+    ret
+}
+
+/// Pick the item that is active at `frame` from a list of keyframes.
+///
+/// Items without a frame index are at frame 0. If every item starts after
+/// `frame`, the first one is used.
+fn keyframe<T>(items: &[T], frame: u32, frame_index: impl Fn(&T) -> Option<u32>) -> Option<&T> {
+    items
+        .iter()
+        .filter(|item| frame_index(item).unwrap_or(0) <= frame)
+        .max_by_key(|item| frame_index(item).unwrap_or(0))
+        .or(items.first())
+}
+
+/// Walk the scene graph from `node` and collect the model instances.
+///
+/// `path` has the nodes from the root to `node`. Nodes can be shared by
+/// several parents, but a node that leads back to itself is an error.
+fn collect(
+    data: &DotVoxData,
+    node: u32,
+    frame: u32,
+    placement: &Placement,
+    name: Option<&str>,
+    path: &mut Vec<u32>,
+    instances: &mut Vec<Instance>,
+) -> Result<()> {
+    if path.contains(&node) {
+        bail!("Scene graph has a cycle at node {node}");
+    }
+    let Some(scene_node) = data.scenes.get(node as usize) else {
+        return Ok(());
+    };
+
+    path.push(node);
+    match scene_node {
+        SceneNode::Transform {
+            attributes,
+            frames,
+            child,
+            layer_id,
+        } => {
+            let layer_hidden = data
+                .layers
+                .get(*layer_id as usize)
+                .is_some_and(|layer| layer.hidden());
+            if !layer_hidden && attributes.get("_hidden").is_none_or(|h| h != "1") {
+                let local = keyframe(frames, frame, |f| f.frame_index())
+                    .map(Placement::from_frame)
+                    .unwrap_or_default();
+                let name = attributes.get("_name").map(|s| s.as_str()).or(name);
+                collect(
+                    data,
+                    *child,
+                    frame,
+                    &placement.then(&local),
+                    name,
+                    path,
+                    instances,
+                )?;
+            }
+        }
+        SceneNode::Group { children, .. } => {
+            for &child in children {
+                collect(data, child, frame, placement, name, path, instances)?;
+            }
+        }
+        SceneNode::Shape { models, .. } => {
+            if let Some(shape) = keyframe(models, frame, |m| m.frame_index()) {
+                let idx = shape.model_id as usize;
+                if let Some(model) = data.models.get(idx) {
+                    instances.push(Instance::new(
+                        name.map(|s| s.to_string()),
+                        idx,
+                        *placement,
+                        VoxelGrid::from(model),
+                    ));
+                }
+            }
+        }
+    }
+    path.pop();
+
+    Ok(())
+}
+
+impl Body for Scene {
+    type Value = u8;
+
+    fn sample(&self, pos: Vec3) -> Option<Self::Value> {
+        self.instances
+            .iter()
+            .find_map(|instance| instance.sample(pos))
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        let mut boxes = self.instances.iter().map(|i| i.bounding_box());
+        let Some(first) = boxes.next() else {
+            return Default::default();
+        };
+
+        boxes.fold(first, |acc, b| {
+            BoundingBox::new(acc.min.min(b.min), acc.max.max(b.max))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dot_vox::{Dict, Frame, Model, ShapeModel, Size, Voxel};
+    use glam::vec3;
+
+    fn dict(items: &[(&str, &str)]) -> Dict {
+        items
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn transform(child: u32, attributes: &[(&str, &str)], frame: &[(&str, &str)]) -> SceneNode {
+        SceneNode::Transform {
+            attributes: dict(attributes),
+            frames: vec![Frame::new(dict(frame))],
+            child,
+            layer_id: 0,
+        }
+    }
+
+    #[test]
+    fn scene_graph_placement() {
+        let model = Model {
+            size: Size { x: 4, y: 2, z: 2 },
+            voxels: vec![Voxel {
+                x: 3,
+                y: 0,
+                z: 0,
+                i: 7,
+            }],
+        };
+
+        let data = DotVoxData {
+            version: 150,
+            models: vec![model],
+            palette: Vec::new(),
+            materials: Vec::new(),
+            scenes: vec![
+                transform(1, &[], &[]),
+                SceneNode::Group {
+                    attributes: Dict::new(),
+                    children: vec![2, 4],
+                },
+                transform(3, &[("_name", "plain")], &[("_t", "10 0 0")]),
+                SceneNode::Shape {
+                    attributes: Dict::new(),
+                    models: vec![ShapeModel {
+                        model_id: 0,
+                        attributes: Dict::new(),
+                    }],
+                },
+                // Turned 90 degrees counterclockwise around the z axis.
+                transform(3, &[("_name", "turned")], &[("_t", "0 10 0"), ("_r", "17")]),
+            ],
+            layers: Vec::new(),
+        };
+
+        let scene = Scene::try_from(&data).unwrap();
+        assert_eq!(scene.instances().len(), 2);
+
+        // Model center is at (2, 1, 1), so the voxel is one step from the
+        // placement point towards +x.
+        let plain = scene.find("plain").unwrap();
+        assert_eq!(plain.to_world(ivec3(3, 0, 0)), ivec3(11, -1, -1));
+        assert_eq!(scene.sample(vec3(11.5, -0.5, -0.5)), Some(7));
+
+        let turned = scene.find("turned").unwrap();
+        let pos = turned.to_world(ivec3(3, 0, 0));
+        assert_eq!(pos, ivec3(1, 11, -1));
+        assert_eq!(turned.to_local(pos), ivec3(3, 0, 0));
+        assert_eq!(scene.sample(pos.as_vec3()), Some(7));
+
+        let aabb = scene.bounding_box();
+        assert!(aabb.contains(vec3(11.5, -0.5, -0.5)));
+        assert!(aabb.contains(pos.as_vec3()));
+    }
+
+    #[test]
+    fn models_without_scene_graph() {
+        let data = DotVoxData {
+            version: 150,
+            models: vec![Model {
+                size: Size { x: 4, y: 2, z: 3 },
+                voxels: vec![Voxel {
+                    x: 3,
+                    y: 0,
+                    z: 0,
+                    i: 7,
+                }],
+            }],
+            palette: Vec::new(),
+            materials: Vec::new(),
+            scenes: Vec::new(),
+            layers: Vec::new(),
+        };
+
+        let scene = Scene::try_from(&data).unwrap();
+        let instance = &scene.instances()[0];
+        assert_eq!(instance.to_world(ivec3(3, 0, 0)), ivec3(3, 0, 0));
+        assert_eq!(scene.sample(vec3(3.5, 0.5, 0.5)), Some(7));
+        assert_eq!(scene.bounding_box().min, Vec3::ZERO);
+    }
+
+    #[test]
+    fn scene_graph_cycle() {
+        let data = DotVoxData {
+            version: 150,
+            models: Vec::new(),
+            palette: Vec::new(),
+            materials: Vec::new(),
+            scenes: vec![
+                transform(1, &[], &[]),
+                SceneNode::Group {
+                    attributes: Dict::new(),
+                    children: vec![2, 2],
+                },
+                // Shared by both group children, which is fine, but leads
+                // back to the group.
+                transform(1, &[], &[]),
+            ],
+            layers: Vec::new(),
+        };
+        assert!(Scene::try_from(&data).is_err());
+    }
+
+    #[test]
+    fn animation_keyframes() {
+        let model = |i| Model {
+            size: Size { x: 1, y: 1, z: 1 },
+            voxels: vec![Voxel {
+                x: 0,
+                y: 0,
+                z: 0,
+                i,
+            }],
+        };
+        let shape = |model_id, f| ShapeModel {
+            model_id,
+            attributes: dict(&[("_f", f)]),
+        };
+
+        let data = DotVoxData {
+            version: 150,
+            models: vec![model(1), model(2)],
+            palette: Vec::new(),
+            materials: Vec::new(),
+            scenes: vec![
+                SceneNode::Transform {
+                    attributes: Dict::new(),
+                    frames: vec![
+                        Frame::new(dict(&[("_f", "0")])),
+                        Frame::new(dict(&[("_f", "2"), ("_t", "5 0 0")])),
+                    ],
+                    child: 1,
+                    layer_id: 0,
+                },
+                SceneNode::Shape {
+                    attributes: Dict::new(),
+                    models: vec![shape(0, "0"), shape(1, "3")],
+                },
+            ],
+            layers: Vec::new(),
+        };
+
+        assert_eq!(keyframes(&data), vec![0, 2, 3]);
+
+        let cell = |frame| {
+            let scene = Scene::at_frame(&data, frame).unwrap();
+            let (pos, &val) = scene.instances()[0].grid().iter().next().unwrap();
+            (scene.instances()[0].to_world(pos), val)
+        };
+        assert_eq!(cell(0), (ivec3(0, 0, 0), 1));
+        assert_eq!(cell(1), (ivec3(0, 0, 0), 1));
+        assert_eq!(cell(2), (ivec3(5, 0, 0), 1));
+        assert_eq!(cell(3), (ivec3(5, 0, 0), 2));
+        assert_eq!(cell(10), (ivec3(5, 0, 0), 2));
+    }
+}